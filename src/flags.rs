@@ -0,0 +1,508 @@
+use anyhow::Context;
+use anyhow::Result;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+
+use crate::includes::nest;
+use crate::patterns::pattern_str;
+use crate::patterns::Match;
+use crate::patterns::Targets;
+use crate::problems;
+use crate::problems::index;
+use crate::problems::Problems;
+
+/// A value of a flag and the paths it targets.
+struct Rule {
+    /// index of the file in `Flags::ymls`
+    yml: usize,
+    name: String,
+    /// canonical encoding of the value
+    value: String,
+    targets: Targets,
+}
+
+impl Rule {
+    /// The value as written in YAML, with strings unquoted.
+    fn yaml_value(&self) -> String {
+        match serde_json::from_str(&self.value) {
+            Ok(serde_json::Value::String(value)) => value,
+            _ => self.value.clone(),
+        }
+    }
+}
+
+/// Flag values and their target path patterns.
+#[derive(Default)]
+pub struct Flags {
+    ymls: Vec<Utf8PathBuf>,
+    rules: Vec<Rule>,
+}
+
+/// A matrix file like `flags.yml` by name, with the keys to mount its values under.
+pub struct Matrix {
+    pub name: String,
+    pub flags: Flags,
+    pub prefix: Vec<String>,
+}
+
+impl Matrix {
+    /// The values targeting a path, mounted under the prefix keys.
+    pub fn get(&self, path: &str) -> Result<Option<serde_json::Value>> {
+        Ok(self.flags.get(path)?.map(|value| nest(&self.prefix, value)))
+    }
+}
+
+/// Different values of a flag targeting the same path with equally specific patterns.
+pub struct Conflict {
+    /// the files with the values
+    pub ymls: Vec<Utf8PathBuf>,
+    pub path: String,
+    pub name: String,
+    /// values and the patterns that target the path
+    pub values: Vec<(String, String)>,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "conflicting values for {} at {} in {}:",
+            self.name,
+            self.path,
+            self.ymls
+                .iter()
+                .map(|yml| yml.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        for (value, pattern) in &self.values {
+            write!(f, " {value} ({pattern})")?;
+        }
+        Ok(())
+    }
+}
+
+impl Flags {
+    /// Adds the rules of other files, which win ties with the existing rules.
+    pub fn extend(&mut self, other: Flags) {
+        let offset = self.ymls.len();
+        self.ymls.extend(other.ymls);
+        self.rules.extend(other.rules.into_iter().map(|rule| Rule {
+            yml: rule.yml + offset,
+            ..rule
+        }));
+    }
+
+    /// The files the flags were loaded from, in order.
+    pub fn ymls(&self) -> &[Utf8PathBuf] {
+        &self.ymls
+    }
+
+    /// The files with values of a flag.
+    pub fn ymls_of(&self, name: &str) -> BTreeSet<&Utf8Path> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.name == name)
+            .map(|rule| self.ymls[rule.yml].as_path())
+            .collect()
+    }
+
+    /// The most specific matches of each flag name for a path, in file order.
+    fn candidates(&self, path: &str) -> BTreeMap<&str, Vec<(Match<'_>, &Rule)>> {
+        let mut candidates: BTreeMap<&str, Vec<(Match<'_>, &Rule)>> = BTreeMap::new();
+        for rule in &self.rules {
+            if let Some(m) = rule.targets.matches(path) {
+                let best = candidates.entry(&rule.name).or_default();
+                match best.first() {
+                    Some((existing, _)) if existing.specificity > m.specificity => {}
+                    Some((existing, _)) if existing.specificity == m.specificity => {
+                        best.push((m, rule))
+                    }
+                    _ => *best = vec![(m, rule)],
+                }
+            }
+        }
+        candidates
+    }
+
+    /// The flags targeting a path as an object of flag names to values.
+    /// When several values of a flag target the path, the most specific
+    /// pattern wins and on a tie the last one in the files wins.
+    pub fn get(&self, path: &str) -> Result<Option<serde_json::Value>> {
+        let candidates = self.candidates(path);
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        // convert values to json
+        let mut map = serde_json::Map::new();
+        for (name, best) in candidates {
+            if let Some((_, rule)) = best.last() {
+                map.insert(name.to_string(), serde_json::from_str(&rule.value)?);
+            }
+        }
+        Ok(Some(serde_json::Value::Object(map)))
+    }
+
+    /// Describes the target paths that match none of the given paths.
+    pub fn unknown_targets(&self, paths: &BTreeSet<String>) -> Vec<String> {
+        let mut unknown = Vec::new();
+        for rule in &self.rules {
+            for (pattern, suggestion) in rule.targets.unknown(paths) {
+                unknown.push(unknown_target(
+                    &self.ymls[rule.yml],
+                    &format!("{}: {}", rule.name, rule.yaml_value()),
+                    pattern,
+                    suggestion,
+                ));
+            }
+        }
+        unknown
+    }
+
+    /// The flags with different values targeting a path with equally specific patterns.
+    pub fn conflicts(&self, path: &str) -> Vec<Conflict> {
+        let mut conflicts = Vec::new();
+        for (name, best) in self.candidates(path) {
+            let mut values: Vec<(String, String)> = Vec::new();
+            let mut ymls = Vec::new();
+            for (m, rule) in best {
+                if !values.iter().any(|(value, _)| *value == rule.value) {
+                    values.push((rule.value.clone(), m.pattern.to_string()));
+                    let yml = &self.ymls[rule.yml];
+                    if !ymls.contains(yml) {
+                        ymls.push(yml.clone());
+                    }
+                }
+            }
+            if values.len() > 1 {
+                conflicts.push(Conflict {
+                    ymls,
+                    path: path.to_string(),
+                    name: name.to_string(),
+                    values,
+                });
+            }
+        }
+        conflicts
+    }
+}
+
+/// Describes a target path that matches no environment directory or ancestor.
+pub fn unknown_target(
+    yml: &Utf8Path,
+    key: &str,
+    pattern: &str,
+    suggestion: Option<&str>,
+) -> String {
+    let mut message = format!("unknown target path {pattern} for {key} in {yml}");
+    if let Some(suggestion) = suggestion {
+        message.push_str(&format!(", did you mean {suggestion}?"));
+    }
+    message
+}
+
+/// Loads a file like `flags.yml` or `versions.yml` that maps each name to values
+/// and each value to the paths it applies to. Values keep their YAML types.
+pub fn load_flags(yml: &Utf8Path) -> Result<Flags> {
+    let source = fs::read_to_string(yml).with_context(|| format!("reading file {yml}"))?;
+    parse_flags(yml, &source, |_| Ok(()))
+}
+
+/// Parses the source of a flags file, checking each value with `check`.
+pub fn parse_flags(
+    yml: &Utf8Path,
+    source: &str,
+    check: impl Fn(&serde_yaml::Value) -> Result<(), String>,
+) -> Result<Flags> {
+    let mut value: serde_yaml::Value =
+        serde_yaml::from_str(source).with_context(|| format!("reading yml {yml}"))?;
+    value
+        .apply_merge()
+        .with_context(|| format!("merging keys in {yml}"))?;
+
+    // an empty file has no flags
+    if value.is_null() {
+        value = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+    let mut problems = Problems::new(yml, source);
+    let mut flags = Flags {
+        ymls: vec![yml.to_path_buf()],
+        rules: Vec::new(),
+    };
+    let Some(names) = value.as_mapping() else {
+        problems.push(&[], "mapping of names", &value);
+        return problems.into_result().map(|_| flags);
+    };
+    for (key, values) in names {
+        let key_path = [problems::key(key)];
+        let Some(key) = key.as_str() else {
+            problems.push(&key_path, "string name", key);
+            continue;
+        };
+        let Some(values) = values.as_mapping() else {
+            problems.push(&key_path, "mapping of values", values);
+            continue;
+        };
+        for (value, paths) in values {
+            let value_path = [key_path[0].clone(), problems::key(value)];
+            if let Err(message) = check(value) {
+                problems.push_message(&value_path, message);
+                continue;
+            }
+            let Ok(value) = canonical_key(value) else {
+                problems.push(&value_path, "value", value);
+                continue;
+            };
+            let Some(paths) = paths.as_sequence() else {
+                problems.push(&value_path, "sequence of paths", paths);
+                continue;
+            };
+            let mut patterns = Vec::new();
+            for (i, path) in paths.iter().enumerate() {
+                let path_path = [value_path[0].clone(), value_path[1].clone(), index(i)];
+                match pattern_str(path) {
+                    Some(path) => match Targets::new(&[&path]) {
+                        Ok(_) => patterns.push(path),
+                        Err(err) => problems.push_message(&path_path, format!("{err:#}")),
+                    },
+                    None => problems.push(&path_path, "string path", path),
+                }
+            }
+            match Targets::new(&patterns) {
+                Ok(targets) => flags.rules.push(Rule {
+                    yml: 0,
+                    name: key.to_string(),
+                    value,
+                    targets,
+                }),
+                Err(err) => problems.push_message(&value_path, format!("{err:#}")),
+            }
+        }
+    }
+    problems.into_result()?;
+    Ok(flags)
+}
+
+/// The canonical encoding of a value used as a YAML key, compact JSON.
+/// `3` and `"3"` are different values with different encodings.
+pub fn canonical_key(key: &serde_yaml::Value) -> Result<String> {
+    let value = crate::yaml::to_json(key.clone())?;
+    Ok(serde_json::to_string(&value)?)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn flags(yml: &str) -> Result<Flags> {
+        parse_flags(Utf8Path::new("flags.yml"), yml, |_| Ok(()))
+    }
+
+    #[test]
+    fn test_typed_values() -> Result<()> {
+        let flags = flags(
+            r#"
+replicas:
+  3: [environments/prod]
+  "1": [environments/dev]
+ratio:
+  0.5: [environments/prod]
+enabled:
+  true: [environments/prod]
+  false: [environments/dev]
+owner:
+  null: [environments/dev]
+zones:
+  [1, 2]: [environments/prod]
+sku:
+  {name: P1, tier: premium}: [environments/prod]
+"#,
+        )?;
+        assert_eq!(
+            flags.get("environments/prod")?.unwrap(),
+            json!({
+                "replicas": 3,
+                "ratio": 0.5,
+                "enabled": true,
+                "zones": [1, 2],
+                "sku": {"name": "P1", "tier": "premium"}
+            })
+        );
+        assert_eq!(
+            flags.get("environments/dev")?.unwrap(),
+            json!({"replicas": "1", "enabled": false, "owner": null})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_patterns() -> Result<()> {
+        let flags = flags(
+            r#"
+featureX:
+  true:
+    - environments/prod/**
+    - !environments/prod/canary
+  false:
+    - environments/**
+replicas:
+  3: [environments/prod/*]
+  5: [environments/prod/westus]
+  7: [environments/*/westus]
+"#,
+        )?;
+        assert_eq!(flags.get("environments")?, None);
+        assert_eq!(
+            flags.get("environments/prod")?.unwrap(),
+            json!({"featureX": false})
+        );
+        // more literal segments win
+        assert_eq!(
+            flags.get("environments/prod/eastus")?.unwrap(),
+            json!({"featureX": true, "replicas": 3})
+        );
+        // negated paths fall back to other matching values
+        assert_eq!(
+            flags.get("environments/prod/canary")?.unwrap(),
+            json!({"featureX": false, "replicas": 3})
+        );
+        // exact paths win over globs
+        assert_eq!(
+            flags.get("environments/prod/westus")?.unwrap(),
+            json!({"featureX": true, "replicas": 5})
+        );
+        assert_eq!(
+            flags.get("environments/dev/westus")?.unwrap(),
+            json!({"featureX": false, "replicas": 7})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tie() -> Result<()> {
+        // the last value in the file wins on a tie
+        let flags = flags("featureX: {true: [environments/*], false: [environments/*]}")?;
+        assert_eq!(
+            flags.get("environments/prod")?.unwrap(),
+            json!({"featureX": false})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_extend() -> Result<()> {
+        let mut flags = parse_flags(
+            Utf8Path::new("flags/a.yml"),
+            "featureX: {true: [environments/*]}\nreplicas: {3: [environments/prod]}",
+            |_| Ok(()),
+        )?;
+        flags.extend(parse_flags(
+            Utf8Path::new("flags/b.yml"),
+            "featureX: {false: [environments/*]}",
+            |_| Ok(()),
+        )?);
+        assert_eq!(
+            flags.get("environments/prod")?.unwrap(),
+            json!({"featureX": false, "replicas": 3})
+        );
+        assert_eq!(
+            flags.ymls_of("featureX").into_iter().collect::<Vec<_>>(),
+            vec!["flags/a.yml", "flags/b.yml"]
+        );
+        assert_eq!(
+            flags.conflicts("environments/prod")[0].to_string(),
+            "conflicting values for featureX at environments/prod in flags/a.yml, flags/b.yml: true (environments/*) false (environments/*)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_targets() -> Result<()> {
+        let flags =
+            flags("api:\n  \"1.3.0\": [environments/prd]\nreplicas:\n  3: [environments/qa]\n")?;
+        let paths = BTreeSet::from(["environments/prod".to_string()]);
+        assert_eq!(
+            flags.unknown_targets(&paths),
+            vec![
+                "unknown target path environments/prd for api: 1.3.0 in flags.yml, did you mean environments/prod?",
+                "unknown target path environments/qa for replicas: 3 in flags.yml",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicts() -> Result<()> {
+        let flags = flags(
+            r#"
+featureX:
+  true: [environments/prod, environments/d*/westus]
+  false: [environments/prod, environments/*v/westus]
+replicas:
+  3: [environments/prod]
+  5: [environments/prod/*]
+"#,
+        )?;
+        let conflicts = flags
+            .conflicts("environments/prod")
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            conflicts,
+            vec!["conflicting values for featureX at environments/prod in flags.yml: true (environments/prod) false (environments/prod)"]
+        );
+        // overlapping patterns of the same specificity
+        let conflicts = flags.conflicts("environments/dev/westus");
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(
+            conflicts[0].values,
+            vec![
+                ("true".to_string(), "environments/d*/westus".to_string()),
+                ("false".to_string(), "environments/*v/westus".to_string())
+            ]
+        );
+        // a more specific pattern is an override, not a conflict
+        assert!(flags.conflicts("environments/prod/westus").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty() -> Result<()> {
+        assert!(flags("")?.rules.is_empty());
+        assert!(flags("# no flags yet\n")?.rules.is_empty());
+        let err = flags("replicas: 3").err().unwrap();
+        assert!(err.to_string().starts_with("1 problem in flags.yml:"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_problems() {
+        let err = flags(
+            r#"
+featureX:
+  true:
+    - environments/prod
+    - [environments/dev]
+    - environments/[prod
+  false: environments/test
+replicas: 3
+"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "4 problems in flags.yml:
+  flags.yml:5: featureX.true[1]: expected string path, found sequence
+  flags.yml:6: featureX.true[2]: invalid path pattern environments/[prod: error parsing glob 'environments/[prod': unclosed character class; missing ']'
+  flags.yml:7: featureX.false: expected sequence of paths, found string
+  flags.yml:8: replicas: expected mapping of values, found number"
+        );
+    }
+}
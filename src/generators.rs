@@ -0,0 +1,87 @@
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// The file name of generators: an environment directory with a `matrix.yml` is a template
+/// expanded into a virtual subdirectory per instance instead of being rendered itself.
+pub const GENERATOR: &str = "matrix.yml";
+
+/// `matrix.yml`, listing instances by name with their parameters.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Generator {
+    instances: BTreeMap<String, Option<serde_json::Map<String, serde_json::Value>>>,
+}
+
+/// A virtual environment directory expanded from a generator.
+#[derive(Debug)]
+pub struct Instance {
+    pub yml_path: Utf8PathBuf,
+    pub name: String,
+    /// merged as the environment files of the directory and available as template variables
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Instance {
+    /// The source of the parameters, like `environments/prod/regions/matrix.yml#westus2`.
+    pub fn source(&self) -> String {
+        format!("{}#{}", self.yml_path, self.name)
+    }
+}
+
+/// Parses a generator into its instances.
+pub fn parse_generator(yml_path: &Utf8Path, json: serde_json::Value) -> Result<Vec<Instance>> {
+    let generator: Generator =
+        serde_json::from_value(json).with_context(|| format!("reading generator {yml_path}"))?;
+    let mut instances = Vec::new();
+    for (name, params) in generator.instances {
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            bail!("invalid instance name {name:?} in {yml_path}");
+        }
+        instances.push(Instance {
+            yml_path: yml_path.to_path_buf(),
+            name,
+            params: params.unwrap_or_default(),
+        });
+    }
+    Ok(instances)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_generator() -> Result<()> {
+        let yml_path = Utf8Path::new("environments/prod/regions/matrix.yml");
+        let instances = parse_generator(
+            yml_path,
+            json!({"instances": {"westus2": {"region": "westus2", "replicas": 3}, "eastus2": null}}),
+        )?;
+        let names = instances
+            .iter()
+            .map(|i| i.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["eastus2", "westus2"]);
+        assert!(instances[0].params.is_empty());
+        assert_eq!(instances[1].params["replicas"], json!(3));
+        assert_eq!(
+            instances[1].source(),
+            "environments/prod/regions/matrix.yml#westus2"
+        );
+
+        let err = parse_generator(yml_path, json!({"instances": {"a/b": {}}})).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid instance name "a/b" in environments/prod/regions/matrix.yml"#
+        );
+        assert!(parse_generator(yml_path, json!({"instance": {}})).is_err());
+        assert!(parse_generator(yml_path, json!({"instances": {"a": [1]}})).is_err());
+        Ok(())
+    }
+}
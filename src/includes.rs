@@ -0,0 +1,482 @@
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use glob::glob;
+use serde_yaml::Value;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::flags::unknown_target;
+use crate::list_yml_paths;
+use crate::patterns::Targets;
+use crate::problems;
+use crate::problems::did_you_mean;
+use crate::problems::index;
+use crate::problems::Problems;
+use crate::yaml::to_json;
+
+/// An entry of `include.yml`, either a path or an object with options.
+#[derive(Debug, Default)]
+struct Entry {
+    /// a yml file, a directory of yml files or a glob, relative to the ev2 root
+    path: String,
+    /// whether the path may match no yml files
+    optional: bool,
+    /// keys to mount the included content at, from a JSON pointer such as `/services/cache`
+    /// or dotted keys such as `services.cache`
+    at: Vec<String>,
+    /// template variables available only while rendering the included content
+    params: serde_json::Map<String, serde_json::Value>,
+}
+
+/// An included yml file.
+pub struct Include {
+    pub yml_path: Utf8PathBuf,
+    /// keys to mount the content at
+    pub at: Vec<String>,
+    /// template variables for rendering the content, see [`crate::jinga::render_params`]
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Include {
+    /// Nests a value under the keys it is mounted at.
+    pub fn nest(&self, value: serde_json::Value) -> serde_json::Value {
+        nest(&self.at, value)
+    }
+}
+
+/// Nests a value under keys.
+pub fn nest(keys: &[String], mut value: serde_json::Value) -> serde_json::Value {
+    for key in keys.iter().rev() {
+        let mut map = serde_json::Map::new();
+        map.insert(key.clone(), value);
+        value = serde_json::Value::Object(map);
+    }
+    value
+}
+
+/// The yml files included for a key of an include file.
+struct Key {
+    include_yml: Utf8PathBuf,
+    key: String,
+    targets: Targets,
+    includes: Vec<Include>,
+}
+
+/// Included yml files by target path patterns.
+#[derive(Default)]
+pub struct Includes {
+    keys: Vec<Key>,
+}
+
+impl Includes {
+    /// Adds the includes of another include file, included after the existing ones.
+    pub fn extend(&mut self, other: Includes) {
+        self.keys.extend(other.keys);
+    }
+
+    /// The yml files included for a target path, in the order of the include files.
+    pub fn get(&self, path: &str) -> impl Iterator<Item = &Include> {
+        let path = path.to_string();
+        self.keys
+            .iter()
+            .filter(move |key| key.targets.matches(&path).is_some())
+            .flat_map(|key| &key.includes)
+    }
+
+    /// Describes the target paths that match none of the given paths.
+    pub fn unknown_targets(&self, paths: &BTreeSet<String>) -> Vec<String> {
+        let mut unknown = Vec::new();
+        for key in &self.keys {
+            for (pattern, suggestion) in key.targets.unknown(paths) {
+                unknown.push(unknown_target(
+                    &key.include_yml,
+                    &key.key,
+                    pattern,
+                    suggestion,
+                ));
+            }
+        }
+        unknown
+    }
+}
+
+/// Loads an include file like `include.yml`, which maps each target path to a list of
+/// yml files, directories of yml files and globs relative to the ev2 root, included in order.
+/// A key may hold several whitespace separated target path patterns.
+pub fn load_includes(ev2_path: &Utf8Path, include_yml: &Utf8Path) -> Result<Includes> {
+    let source =
+        fs::read_to_string(include_yml).with_context(|| format!("reading file {include_yml}"))?;
+    let entries = parse_includes(include_yml, &source)?;
+
+    let mut paths_cache: HashMap<String, Vec<Utf8PathBuf>> = HashMap::new();
+    let mut includes = Includes::default();
+    for (key, entries) in entries {
+        let mut combined = Vec::new();
+        for entry in entries {
+            let paths = match paths_cache.get(&entry.path) {
+                Some(paths) => paths.clone(),
+                None => {
+                    let paths = expand(ev2_path, &entry.path)?;
+                    paths_cache.insert(entry.path.clone(), paths.clone());
+                    paths
+                }
+            };
+            if paths.is_empty() && !entry.optional {
+                let mut message = format!(
+                    "{} included for {key} in {include_yml} does not match any yml files",
+                    entry.path
+                );
+                if let Some(suggestion) = suggest_path(ev2_path, &entry.path) {
+                    message.push_str(&format!(", did you mean {suggestion}?"));
+                }
+                bail!(message);
+            }
+            combined.extend(paths.into_iter().map(|yml_path| Include {
+                yml_path,
+                at: entry.at.clone(),
+                params: entry.params.clone(),
+            }));
+        }
+        let targets = Targets::parse(&key).with_context(|| format!("loading {include_yml}"))?;
+        includes.keys.push(Key {
+            include_yml: include_yml.to_path_buf(),
+            key,
+            targets,
+            includes: combined,
+        });
+    }
+    Ok(includes)
+}
+
+/// The yml files of an include path in a defined order:
+/// a file, the files of a directory sorted by path, or the matches of a glob sorted by path.
+fn expand(ev2_path: &Utf8Path, path: &str) -> Result<Vec<Utf8PathBuf>> {
+    let include_path = ev2_path.join(path);
+    let mut paths = Vec::new();
+    if path.contains(['*', '?', '[']) {
+        for matched in glob(include_path.as_str())
+            .with_context(|| format!("invalid include glob {path}"))?
+            .flatten()
+        {
+            let Ok(matched) = Utf8PathBuf::from_path_buf(matched) else {
+                continue;
+            };
+            if matched.is_dir() {
+                paths.extend(list_yml_paths(&matched));
+            } else if matched.extension() == Some("yml") {
+                paths.push(matched);
+            }
+        }
+    } else if include_path.is_file() {
+        paths.push(include_path);
+    } else if include_path.is_dir() {
+        paths.extend(list_yml_paths(&include_path));
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// Corrects likely typos in the components of a path that does not exist.
+fn suggest_path(ev2_path: &Utf8Path, path: &str) -> Option<String> {
+    let mut suggestion = Utf8PathBuf::new();
+    for component in Utf8Path::new(path).components() {
+        let component = component.as_str();
+        if ev2_path.join(&suggestion).join(component).exists() {
+            suggestion.push(component);
+            continue;
+        }
+        let names = ev2_path
+            .join(&suggestion)
+            .read_dir_utf8()
+            .ok()?
+            .flatten()
+            .map(|entry| entry.file_name().to_string())
+            .collect::<Vec<_>>();
+        suggestion.push(did_you_mean(component, names.iter().map(|n| n.as_str()))?);
+    }
+    Some(suggestion.to_string())
+}
+
+fn parse_includes(include_yml: &Utf8Path, source: &str) -> Result<Vec<(String, Vec<Entry>)>> {
+    let mut value: Value =
+        serde_yaml::from_str(source).with_context(|| format!("reading yml {include_yml}"))?;
+    value
+        .apply_merge()
+        .with_context(|| format!("merging keys in {include_yml}"))?;
+
+    // an empty file has no includes
+    if value.is_null() {
+        value = Value::Mapping(serde_yaml::Mapping::new());
+    }
+    let mut problems = Problems::new(include_yml, source);
+    let mut include_entries = Vec::new();
+    let Some(targets) = value.as_mapping() else {
+        problems.push(&[], "mapping of target paths", &value);
+        return problems.into_result().map(|_| include_entries);
+    };
+    for (key, values) in targets {
+        let key_path = [problems::key(key)];
+        let Some(key) = key.as_str() else {
+            problems.push(&key_path, "string target path", key);
+            continue;
+        };
+        if let Err(err) = Targets::parse(key) {
+            problems.push_message(&key_path, format!("{err:#}"));
+        }
+        let Some(values) = values.as_sequence() else {
+            problems.push(&key_path, "sequence of include paths", values);
+            continue;
+        };
+        let mut entries = Vec::new();
+        for (i, value) in values.iter().enumerate() {
+            let entry_path = [key_path[0].clone(), index(i)];
+            if let Some(entry) = parse_entry(value, &entry_path, &mut problems) {
+                entries.push(entry);
+            }
+        }
+        include_entries.push((key.to_string(), entries));
+    }
+    problems.into_result()?;
+    Ok(include_entries)
+}
+
+fn parse_entry(value: &Value, entry_path: &[String], problems: &mut Problems) -> Option<Entry> {
+    if let Some(path) = value.as_str() {
+        return Some(Entry {
+            path: path.to_string(),
+            ..Default::default()
+        });
+    }
+    let Some(options) = value.as_mapping() else {
+        problems.push(entry_path, "include path or mapping of options", value);
+        return None;
+    };
+    let mut entry = Entry::default();
+    let mut has_path = false;
+    let mut mounts = 0;
+    for (option, value) in options {
+        let option_name = problems::key(option);
+        let mut option_path = entry_path.to_vec();
+        option_path.push(option_name.clone());
+        match option_name.as_str() {
+            "path" => match value.as_str() {
+                Some(path) => {
+                    entry.path = path.to_string();
+                    has_path = true;
+                }
+                None => problems.push(&option_path, "string include path", value),
+            },
+            "optional" => match value.as_bool() {
+                Some(optional) => entry.optional = optional,
+                None => problems.push(&option_path, "boolean", value),
+            },
+            "at" => match value.as_str().map(parse_pointer) {
+                Some(Ok(at)) => {
+                    entry.at = at;
+                    mounts += 1;
+                }
+                Some(Err(message)) => problems.push_message(&option_path, message),
+                None => problems.push(&option_path, "string JSON pointer", value),
+            },
+            "params" => match value.as_mapping().map(|_| to_json(value.clone())) {
+                Some(Ok(serde_json::Value::Object(params))) => entry.params = params,
+                Some(Err(err)) => problems.push_message(&option_path, err.to_string()),
+                _ => problems.push(&option_path, "mapping of template variables", value),
+            },
+            "prefix" => match value.as_str() {
+                Some(prefix) => {
+                    entry.at = prefix.split('.').map(|key| key.to_string()).collect();
+                    mounts += 1;
+                }
+                None => problems.push(&option_path, "string of dotted keys", value),
+            },
+            _ => problems.push_message(&option_path, format!("unknown option {option_name}")),
+        }
+    }
+    if mounts > 1 {
+        problems.push_message(entry_path, "prefix and at are exclusive".to_string());
+    }
+    if !has_path {
+        problems.push_message(entry_path, "missing path".to_string());
+        return None;
+    }
+    Some(entry)
+}
+
+/// The keys of a JSON pointer such as `/services/cache`, unescaping `~1` and `~0`.
+pub fn parse_pointer(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(keys) = pointer.strip_prefix('/') else {
+        return Err(format!("JSON pointer {pointer} does not start with /"));
+    };
+    Ok(keys
+        .split('/')
+        .map(|key| key.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::yaml::test::temp_ev2;
+
+    fn include(yml_path: &str) -> Include {
+        Include {
+            yml_path: yml_path.into(),
+            at: Vec::new(),
+            params: serde_json::Map::new(),
+        }
+    }
+
+    #[test]
+    fn test_get() -> Result<()> {
+        let key = |key: &str, yml_path| -> Result<Key> {
+            Ok(Key {
+                include_yml: "include.yml".into(),
+                key: key.to_string(),
+                targets: Targets::parse(key)?,
+                includes: vec![include(yml_path)],
+            })
+        };
+        let includes = Includes {
+            keys: vec![
+                key("environments", "base.yml")?,
+                key("environments/prod/** !environments/prod/canary", "prod.yml")?,
+            ],
+        };
+        let get = |path| {
+            includes
+                .get(path)
+                .map(|i| i.yml_path.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(get("environments"), vec!["base.yml"]);
+        assert_eq!(get("environments/prod/westus"), vec!["prod.yml"]);
+        assert!(get("environments/prod/canary").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let ev2_path = temp_ev2(
+            "includes",
+            &[
+                (
+                    "include.yml",
+                    r#"
+environments:
+  - shared/network-*.yml
+  - shared/base
+  - shared/redis.yml
+  - path: shared/cache.yml
+    prefix: services.cache
+  - path: shared/redis.yml
+    at: /services/sessions~1cache
+    params:
+      name: sessions
+  - path: shared/missing
+    optional: true
+"#,
+                ),
+                ("shared/network-b.yml", "b: 1"),
+                ("shared/network-a.yml", "a: 1"),
+                ("shared/base/z.yml", "z: 1"),
+                ("shared/base/a/b.yml", "b: 1"),
+                ("shared/redis.yml", "redis: 1"),
+                ("shared/cache.yml", "size: 1"),
+            ],
+        )?;
+        let includes = load_includes(&ev2_path, &ev2_path.join("include.yml"))?;
+        let included = includes
+            .get("environments")
+            .map(|i| {
+                let path = i.yml_path.strip_prefix(&ev2_path).unwrap();
+                let params = serde_json::Value::Object(i.params.clone());
+                format!("{path} {} {params}", i.at.join("."))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            included,
+            vec![
+                "shared/network-a.yml  {}",
+                "shared/network-b.yml  {}",
+                "shared/base/a/b.yml  {}",
+                "shared/base/z.yml  {}",
+                "shared/redis.yml  {}",
+                "shared/cache.yml services.cache {}",
+                "shared/redis.yml services.sessions/cache {\"name\":\"sessions\"}",
+            ]
+        );
+
+        fs::write(
+            ev2_path.join("include.yml"),
+            "environments: [shard/rdeis.yml]",
+        )?;
+        let err = load_includes(&ev2_path, &ev2_path.join("include.yml"))
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .ends_with("does not match any yml files, did you mean shared/redis.yml?"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nest() {
+        let include = Include {
+            yml_path: "shared/cache.yml".into(),
+            at: vec!["services".into(), "cache".into()],
+            params: serde_json::Map::new(),
+        };
+        assert_eq!(
+            include.nest(serde_json::json!({"size": 1})),
+            serde_json::json!({"services": {"cache": {"size": 1}}})
+        );
+    }
+
+    #[test]
+    fn test_empty() -> Result<()> {
+        assert!(parse_includes(Utf8Path::new("include.yml"), "")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_problems() {
+        let source = r#"
+environments:
+  - shared/base
+  - network: shared/network
+  - path: shared/prod
+    optional: yes please
+  - path: shared/redis.yml
+    at: services/cache
+  - path: shared/redis.yml
+    at: /services/cache
+    prefix: services.cache
+  - path: shared/redis.yml
+    params: [sessions]
+environments/prod: shared/prod
+environments/[dev: [shared/dev]
+"#;
+        let err = parse_includes(Utf8Path::new("include.yml"), source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "8 problems in include.yml:
+  include.yml:4: environments[1].network: unknown option network
+  include.yml:4: environments[1]: missing path
+  include.yml:6: environments[2].optional: expected boolean, found string
+  include.yml:8: environments[3].at: JSON pointer services/cache does not start with /
+  include.yml:9: environments[4]: prefix and at are exclusive
+  include.yml:13: environments[5].params: expected mapping of template variables, found sequence
+  include.yml:14: environments/prod: expected sequence of include paths, found string
+  include.yml:15: environments/[dev: invalid path pattern environments/[dev: error parsing glob 'environments/[dev': unclosed character class; missing ']'"
+        );
+    }
+}
@@ -6,11 +6,15 @@ use minijinja::ErrorKind;
 use minijinja::{Environment, Value};
 use serde_json_merge::Dfs;
 use serde_json_merge::Iter;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::str::FromStr;
 
+use crate::versions::parse_version;
+
 #[derive(Default)]
 struct VarNodes {
     named_nodes: HashMap<String, Node<String>>,
@@ -37,12 +41,14 @@ impl VarNodes {
         value: &mut serde_json::Value,
     ) -> anyhow::Result<Graph> {
         let mut globals: HashMap<String, serde_json::Value> = HashMap::new();
+        let mut pointers: HashMap<String, String> = HashMap::new();
         let mut invalid_templates = BTreeSet::new();
 
         value.iter::<Dfs>().for_each(|(path, value)| {
             if let Some(name) = path.last() {
                 let name = name.to_string();
                 globals.insert(name.clone(), value.clone());
+                pointers.insert(name.clone(), pointer(path.iter()));
                 let vars = variables(env, value, &mut invalid_templates);
                 for var in &vars {
                     self.get_or_create(var);
@@ -58,6 +64,7 @@ impl VarNodes {
         Ok(Graph {
             dep_graph: DepGraph::new(&nodes),
             globals,
+            pointers,
         })
     }
 }
@@ -65,6 +72,14 @@ impl VarNodes {
 struct Graph {
     dep_graph: DepGraph<String>,
     globals: HashMap<String, serde_json::Value>,
+    /// the JSON pointer of each global
+    pointers: HashMap<String, String>,
+}
+
+/// The JSON pointer of a path.
+fn pointer<K: ToString>(keys: impl Iterator<Item = K>) -> String {
+    keys.map(|key| format!("/{}", key.to_string().replace('~', "~0").replace('/', "~1")))
+        .collect()
 }
 
 fn create_env<'s>() -> Environment<'s> {
@@ -75,11 +90,81 @@ fn create_env<'s>() -> Environment<'s> {
     env.add_filter("nthhost", nthhost);
     env.add_filter("ipaddr", ipaddr);
     env.add_filter("ipsubnet", ipsubnet);
+    env.add_filter("major", major);
+    env.add_filter("minor", minor);
+    env.add_filter("patch", patch);
+    env.add_filter("bump_major", bump_major);
+    env.add_filter("bump_minor", bump_minor);
+    env.add_filter("bump_patch", bump_patch);
+    env.add_filter("concat", concat);
+    env.add_test("version_eq", |a: String, b: String| {
+        compare(&a, &b).map(|o| o.is_eq())
+    });
+    env.add_test("version_gt", |a: String, b: String| {
+        compare(&a, &b).map(|o| o.is_gt())
+    });
+    env.add_test("version_gte", |a: String, b: String| {
+        compare(&a, &b).map(|o| o.is_ge())
+    });
+    env.add_test("version_lt", |a: String, b: String| {
+        compare(&a, &b).map(|o| o.is_lt())
+    });
+    env.add_test("version_lte", |a: String, b: String| {
+        compare(&a, &b).map(|o| o.is_le())
+    });
     env
 }
 
-fn create_ctx() -> Value {
-    Value::default()
+fn create_ctx(ctx: &serde_json::Value) -> BTreeMap<String, Value> {
+    let mut map = BTreeMap::new();
+    if let Some(ctx) = ctx.as_object() {
+        for (key, value) in ctx {
+            map.insert(key.clone(), Value::from_serializable(value));
+        }
+    }
+    map
+}
+
+/// Adds an `environment(dir)` function returning the rendered config of other environment
+/// directories, as a global so that keys named `environment` take precedence over it.
+fn add_environment(env: &mut Environment, environments: BTreeMap<String, serde_json::Value>) {
+    env.add_function("environment", move |dir: String| -> Result<Value, Error> {
+        match environments.get(dir.trim_matches('/')) {
+            Some(config) => Ok(Value::from_serializable(config)),
+            None => Err(Error::new(
+                ErrorKind::InvalidOperation,
+                format!("environment directory {dir} is not rendered before this one"),
+            )),
+        }
+    });
+}
+
+/// The environment directories referenced by `environment('dir')` calls in templates.
+pub fn environment_refs(value: &serde_json::Value) -> BTreeSet<String> {
+    let mut refs = BTreeSet::new();
+    let mut scan = |tmpl: &str| {
+        if !tmpl.contains("{{") {
+            return;
+        }
+        for (i, _) in tmpl.match_indices("environment(") {
+            let args = tmpl[i + "environment(".len()..].trim_start();
+            let Some(quote) = args.chars().next().filter(|c| *c == '\'' || *c == '"') else {
+                continue;
+            };
+            if let Some(end) = args[1..].find(quote) {
+                refs.insert(args[1..end + 1].trim_matches('/').to_string());
+            }
+        }
+    };
+    value.iter_recursive::<Dfs>().for_each(|(path, value)| {
+        if let Some(key) = path.last() {
+            scan(&key.to_string());
+        }
+        if let Some(value) = value.as_str() {
+            scan(value);
+        }
+    });
+    refs
 }
 
 // get undeclared variables from Value
@@ -103,17 +188,176 @@ fn variables(
     vars
 }
 
+/// Renders the templates whose variables are all params, such as the params of an include.
+/// Templates also using other variables are left for [`render`], with the params they use
+/// bound by `{% set %}` statements so that they do not resolve to other values.
+pub fn render_params(
+    value: &mut serde_json::Value,
+    params: &serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<()> {
+    let env = create_env();
+    let ctx = Value::from_serializable(params);
+    let mut errors = Vec::new();
+    value
+        .mutate_recursive::<Dfs>()
+        .for_each(|_, value: &mut serde_json::Value| {
+            let Some(tmpl_str) = value.as_str().filter(|v| v.contains("{{")) else {
+                return;
+            };
+            let Ok(tmpl) = env.template_from_str(tmpl_str) else {
+                return;
+            };
+            let vars = tmpl.undeclared_variables(false);
+            if !vars.iter().all(|var| params.contains_key(var)) {
+                let mut vars = vars.into_iter().collect::<Vec<_>>();
+                vars.sort();
+                let mut bound = String::new();
+                for var in vars {
+                    if let Some(param) = params.get(&var) {
+                        bound.push_str(&format!("{{% set {var} = {} %}}", literal(param)));
+                    }
+                }
+                if !bound.is_empty() {
+                    *value = serde_json::Value::String(bound + tmpl_str);
+                }
+                return;
+            }
+            if vars.is_empty() {
+                return;
+            }
+            match tmpl.render(&ctx) {
+                Ok(rendered) => *value = serde_json::Value::String(rendered),
+                Err(err) => errors.push(format!("{tmpl_str}: {err}")),
+            }
+        });
+    if !errors.is_empty() {
+        return Err(anyhow!("render errors: {errors:?}"));
+    }
+    Ok(())
+}
+
+/// A jinja expression for a value.
+fn literal(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "none".to_string(),
+        serde_json::Value::Array(items) => {
+            let items = items.iter().map(literal).collect::<Vec<_>>();
+            format!("[{}]", items.join(", "))
+        }
+        serde_json::Value::Object(map) => {
+            let entries = map
+                .iter()
+                .map(|(key, value)| format!("{}: {}", literal(&key.clone().into()), literal(value)))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", entries.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+/// The values overridden by templates calling `inherited()`, oldest first, by JSON pointer.
+pub type Inherited = HashMap<String, Vec<serde_json::Value>>;
+
+/// Removes the values of `current` that templates of `incoming` calling `inherited()`
+/// override, keeping them in `inherited`, so that merging `incoming` replaces them.
+/// Values of `incoming` replacing such templates drop what they inherited.
+pub fn take_inherited(
+    current: &mut serde_json::Value,
+    incoming: &serde_json::Value,
+    pointer: &str,
+    inherited: &mut Inherited,
+) {
+    let (Some(current), Some(incoming)) = (current.as_object_mut(), incoming.as_object()) else {
+        return;
+    };
+    for (key, value) in incoming {
+        let key_pointer = format!("{pointer}/{}", key.replace('~', "~0").replace('/', "~1"));
+        if value.as_str().is_some_and(|v| v.contains("inherited(")) {
+            if let Some(previous) = current.remove(key) {
+                inherited.entry(key_pointer).or_default().push(previous);
+            }
+        } else {
+            if !value.is_object() && !value.is_null() {
+                let nested = format!("{key_pointer}/");
+                inherited.retain(|p, _| *p != key_pointer && !p.starts_with(&nested));
+            }
+            if let Some(previous) = current.get_mut(key) {
+                take_inherited(previous, value, &key_pointer, inherited);
+            }
+        }
+    }
+}
+
+/// The context with an `inherited()` function returning a value, rendered if it is a template.
+fn inherited_ctx(
+    env: &Environment,
+    ctx: &BTreeMap<String, Value>,
+    previous: &[serde_json::Value],
+) -> Result<Value, Error> {
+    let mut inherited = None;
+    for value in previous {
+        let value = match value.as_str() {
+            Some(tmpl) if tmpl.contains("{{") => {
+                Value::from(env.render_str(tmpl, inherited_ctx_value(ctx, &inherited))?)
+            }
+            _ => Value::from_serializable(value),
+        };
+        inherited = Some(value);
+    }
+    Ok(inherited_ctx_value(ctx, &inherited))
+}
+
+/// Renders a template calling `inherited()`, typed like the value it overrode.
+fn render_inherited(
+    env: &Environment,
+    ctx: &BTreeMap<String, Value>,
+    previous: &[serde_json::Value],
+    tmpl: &str,
+) -> Result<serde_json::Value, Error> {
+    let rendered = env.render_str(tmpl, inherited_ctx(env, ctx, previous)?)?;
+    if previous.last().is_some_and(|p| !p.is_string()) {
+        Ok(serde_yaml::from_str::<serde_json::Value>(&rendered).unwrap_or(rendered.into()))
+    } else {
+        Ok(rendered.into())
+    }
+}
+
+fn inherited_ctx_value(ctx: &BTreeMap<String, Value>, inherited: &Option<Value>) -> Value {
+    let mut map = ctx.clone();
+    if let Some(inherited) = inherited.clone() {
+        let inherited =
+            Value::from_function(move || -> Result<Value, Error> { Ok(inherited.clone()) });
+        map.insert("inherited".to_string(), inherited);
+    }
+    Value::from(map)
+}
+
 /// Renders any values that are jinja templates.
-/// The keys are set as global varaibles.
-pub fn render(value: &mut serde_json::Value) -> anyhow::Result<()> {
+/// The keys are set as global varaibles and the context variables, such as
+/// the `configur` namespace, take precedence over them.
+/// Templates calling `inherited()` get the value they overrode, typed when it was not a string,
+/// and `environment(dir)` gets the rendered config of one of the given environment directories.
+pub fn render(
+    value: &mut serde_json::Value,
+    ctx: &serde_json::Value,
+    inherited: &Inherited,
+    environments: BTreeMap<String, serde_json::Value>,
+) -> anyhow::Result<()> {
     let mut env = create_env();
-    let ctx = create_ctx();
+    add_environment(&mut env, environments);
+    let ctx_map = create_ctx(ctx);
+    let ctx = Value::from(ctx_map.clone());
 
     let var_nodes = VarNodes::default();
     let graph = var_nodes.graph(&env, value)?;
     graph.dep_graph.into_iter().for_each(|name| {
         if let Some(global) = graph.globals.get(&name) {
-            if let Some(global) = global.as_str() {
+            let previous = graph.pointers.get(&name).and_then(|p| inherited.get(p));
+            if let (Some(global), Some(previous)) = (global.as_str(), previous) {
+                if let Ok(global) = render_inherited(&env, &ctx_map, previous, global) {
+                    env.add_global(name.clone(), Value::from_serializable(&global));
+                }
+            } else if let Some(global) = global.as_str() {
                 if let Ok(global) = env.render_str(global, &ctx) {
                     env.add_global(name.clone(), global);
                 }
@@ -137,9 +381,14 @@ pub fn render(value: &mut serde_json::Value) -> anyhow::Result<()> {
     let mut render_errors = Vec::new();
     value
         .mutate_recursive::<Dfs>()
-        .for_each(|_path, value: &mut serde_json::Value| {
+        .for_each(|path, value: &mut serde_json::Value| {
             if let Some(val) = value.as_str() {
-                if val.contains("{{") {
+                if let Some(previous) = inherited.get(&pointer(path.iter())) {
+                    match render_inherited(&env, &ctx_map, previous, val) {
+                        Ok(rendered) => *value = rendered,
+                        Err(err) => render_errors.push(err),
+                    }
+                } else if val.contains("{{") {
                     match env.render_str(val, &ctx) {
                         Ok(val) => *value = val.into(),
                         Err(err) => {
@@ -172,6 +421,11 @@ pub fn render(value: &mut serde_json::Value) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn concat(mut list: Vec<Value>, other: Vec<Value>) -> Vec<Value> {
+    list.extend(other);
+    list
+}
+
 fn string(value: &Value) -> Result<String, Error> {
     Ok(value.to_string())
 }
@@ -235,11 +489,53 @@ fn ipsubnet(ip_network: String, prefix_len: u8, incr: usize) -> Result<String, E
     Ok(subnet.to_string())
 }
 
+fn version(version: &str) -> Result<semver::Version, Error> {
+    parse_version(version).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidOperation,
+            format!("invalid semantic version {version}"),
+        )
+        .with_source(err)
+    })
+}
+
+fn compare(a: &str, b: &str) -> Result<Ordering, Error> {
+    Ok(version(a)?.cmp(&version(b)?))
+}
+
+fn major(value: String) -> Result<u64, Error> {
+    Ok(version(&value)?.major)
+}
+
+fn minor(value: String) -> Result<u64, Error> {
+    Ok(version(&value)?.minor)
+}
+
+fn patch(value: String) -> Result<u64, Error> {
+    Ok(version(&value)?.patch)
+}
+
+fn bump_major(value: String) -> Result<String, Error> {
+    let v = version(&value)?;
+    Ok(semver::Version::new(v.major + 1, 0, 0).to_string())
+}
+
+fn bump_minor(value: String) -> Result<String, Error> {
+    let v = version(&value)?;
+    Ok(semver::Version::new(v.major, v.minor + 1, 0).to_string())
+}
+
+fn bump_patch(value: String) -> Result<String, Error> {
+    let v = version(&value)?;
+    Ok(semver::Version::new(v.major, v.minor, v.patch + 1).to_string())
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
     use anyhow::ensure;
     use serde_json::json;
+    use serde_json_merge::Merge;
 
     #[test]
     fn test_ipsubnet() -> anyhow::Result<()> {
@@ -327,7 +623,7 @@ pub mod test {
 
     fn assert_render(tmpl_str: &str, expected: &str) -> anyhow::Result<()> {
         let env = create_env();
-        let ctx = create_ctx();
+        let ctx = Value::from(create_ctx(&json!({})));
         let actual = env.render_str(tmpl_str, ctx)?;
         if actual != expected {
             return Err(anyhow!(
@@ -371,4 +667,136 @@ pub mod test {
         )?;
         Ok(())
     }
+
+    #[test]
+    fn test_versions() -> anyhow::Result<()> {
+        assert_render("{{ '1.2.3' | major }}", "1")?;
+        assert_render("{{ 'v1.2.3' | minor }}", "2")?;
+        assert_render("{{ '1.2.3-beta.1' | patch }}", "3")?;
+        assert_render("{{ '1.2.3' | bump_major }}", "2.0.0")?;
+        assert_render("{{ '1.2.3' | bump_minor }}", "1.3.0")?;
+        assert_render("{{ '1.2.3' | bump_patch }}", "1.2.4")?;
+        assert_render("{{ '1.10.0' is version_gte('1.9.0') }}", "true")?;
+        assert_render("{{ '1.2.0-beta.1' is version_lt('1.2.0') }}", "true")?;
+        assert_render("{{ 'v1.2.0' is version_eq('1.2.0') }}", "true")?;
+        assert_render("{{ '1.2.0' is version_gt('1.2.0') }}", "false")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_ctx() -> anyhow::Result<()> {
+        let mut value = json!({
+            "region": "westus",
+            "storage": "{{ configur.dir_name }}-storage",
+            "location": "{{ region }}/{{ configur.segments[0] }}",
+        });
+        let ctx = json!({"configur": {"dir_name": "westus", "segments": ["prod", "westus"]}});
+        render(&mut value, &ctx, &Inherited::new(), BTreeMap::new())?;
+        assert_eq!(
+            value,
+            json!({
+                "region": "westus",
+                "storage": "westus-storage",
+                "location": "westus/prod",
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_inherited() -> anyhow::Result<()> {
+        let mut value = json!({"name": "api", "replicas": 2, "code": 5, "zones": [1, 2], "tags": {"env": "prod"}});
+        let mut inherited = Inherited::new();
+        for layer in [
+            json!({
+                "name": "{{ inherited() }}-west",
+                "replicas": "{{ inherited() + 1 }}",
+                "code": "{{ inherited() + 1 }}",
+                "zones": "{{ inherited() | concat([3]) }}",
+                "tags": {"team": "web"},
+            }),
+            json!({"name": "{{ inherited() }}-2", "greeting": "hello {{ name }}"}),
+            // replacing a template calling inherited() drops the inherited value
+            json!({"code": "007"}),
+        ] {
+            take_inherited(&mut value, &layer, "", &mut inherited);
+            value = value.merged_recursive::<Dfs>(&layer);
+        }
+        render(&mut value, &json!({}), &inherited, BTreeMap::new())?;
+        assert_eq!(
+            value,
+            json!({
+                "name": "api-west-2",
+                "greeting": "hello api-west-2",
+                "replicas": 3,
+                "code": "007",
+                "zones": [1, 2, 3],
+                "tags": {"env": "prod", "team": "web"},
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_environment() -> anyhow::Result<()> {
+        let mut value = json!({
+            "peer": "{{ environment('prod/westus').network.cidr }}",
+            "{{ environment(\"prod/eastus/\").name }}": true,
+        });
+        assert_eq!(
+            environment_refs(&value),
+            BTreeSet::from(["prod/eastus".to_string(), "prod/westus".to_string()])
+        );
+        let environments = BTreeMap::from([
+            (
+                "prod/westus".to_string(),
+                json!({"network": {"cidr": "10.1.0.0/16"}}),
+            ),
+            ("prod/eastus".to_string(), json!({"name": "east"})),
+        ]);
+        render(&mut value, &json!({}), &Inherited::new(), environments)?;
+        assert_eq!(value, json!({"peer": "10.1.0.0/16", "east": true}));
+
+        // keys named environment take precedence over the function
+        let mut value = json!({"environment": "production", "label": "{{ environment }}-label"});
+        render(&mut value, &json!({}), &Inherited::new(), BTreeMap::new())?;
+        assert_eq!(value["label"], json!("production-label"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_params() -> anyhow::Result<()> {
+        let mut value = serde_json::json!({
+            "name": "{{ name }}-cache",
+            "size": "{{ size * 2 }}",
+            "region": "{{ region }}",
+            "label": "{{ name }} in {{ region }}",
+            "ports": ["{{ port }}", 6380],
+            "tags": "{{ tags.team }} {{ tags.tier is none }} {{ env }}",
+        });
+        let params = serde_json::json!({
+            "name": "sessions",
+            "size": 2,
+            "port": 6379,
+            "tags": {"team": "web \"cache\"", "tier": null},
+        });
+        render_params(&mut value, params.as_object().unwrap())?;
+        assert_eq!(value["name"], json!("sessions-cache"));
+        assert_eq!(value["size"], json!("4"));
+        assert_eq!(value["region"], json!("{{ region }}"));
+        assert_eq!(value["ports"], json!(["6379", 6380]));
+
+        // templates mixing params with other variables use the params when rendered
+        let mut config = json!({
+            "name": "api",
+            "region": "westus",
+            "env": "prod",
+            "label": value["label"],
+            "tags": value["tags"],
+        });
+        render(&mut config, &json!({}), &Inherited::new(), BTreeMap::new())?;
+        assert_eq!(config["label"], json!("sessions in westus"));
+        assert_eq!(config["tags"], json!("web \"cache\" true prod"));
+        Ok(())
+    }
 }
@@ -1,32 +1,495 @@
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use clap::Parser;
+use clap::Subcommand;
 use glob::glob;
+use manifest::Layer;
+use manifest::OutputFormat;
+use serde::Serialize;
 use serde_json::json;
 use serde_json_merge::Dfs;
 use serde_json_merge::Iter;
 use serde_json_merge::Merge;
 use serde_json_merge::SortKeys;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::{collections::BTreeMap, fs, str::FromStr};
 
+mod flags;
+mod generators;
+mod includes;
 mod jinga;
+mod manifest;
+mod overrides;
+mod patterns;
+mod problems;
+mod profiles;
+mod promote;
+mod report;
+mod versions;
+mod yaml;
 
 type JsonCache = HashMap<Utf8PathBuf, serde_json::Value>;
 
+/// The top level key of environment yml files listing the directories they extend.
+const EXTENDS: &str = "extends";
+
 #[derive(Parser)]
 #[command(version)]
 struct Cli {
     #[arg(long)]
     ev2: String,
-    #[arg(short, long, default_value = "environments")]
-    environments: String,
-    #[arg(short, long, default_value = "scratch")]
-    scratch: String,
+    /// Project manifest, relative to the ev2 directory [default: configur.yml if it exists]
+    #[arg(long)]
+    manifest: Option<String>,
+    /// [default: environments]
+    #[arg(short, long)]
+    environments: Option<String>,
+    /// [default: scratch]
+    #[arg(short, long)]
+    scratch: Option<String>,
+    /// Format of the rendered config files [default: json]
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
     #[arg(short, long)]
     verbose: bool,
+    /// Fail when flags or versions have conflicting values for the same path
+    #[arg(long)]
+    deny_conflicts: bool,
+    /// Flags files or globs, relative to the ev2 directory [default: flags.yml if it exists]
+    #[arg(long)]
+    flags: Vec<String>,
+    /// Versions files or globs, relative to the ev2 directory [default: versions.yml if it exists]
+    #[arg(long)]
+    versions: Vec<String>,
+    /// Include files or globs, relative to the ev2 directory [default: include.yml if it exists]
+    #[arg(long)]
+    include: Vec<String>,
+    /// Set a value after all layers, like `path.to.key=value` or `environments/prod/**:path.to.key=value`.
+    /// Values are parsed as YAML. CONFIGUR_SET__path__to__key=value variables set values too.
+    #[arg(long)]
+    set: Vec<String>,
+    /// Set a value to the contents of a file, like `path.to.key=file`
+    #[arg(long)]
+    set_file: Vec<String>,
+    /// Write the sources of each rendered config to provenance.json
+    #[arg(long)]
+    provenance: bool,
+    /// Activate a profile, merging its `*.<profile>.yml` environment files and its overlay tree
+    /// on top of each environment directory. Renders to `<scratch>@<profiles>`
+    #[arg(long)]
+    profile: Vec<String>,
+    /// Skip the untracked `*.local.yml` environment files and `local/` tree, as when CI is set
+    #[arg(long)]
+    no_local: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render the config of each environment directory to the scratch directory (default)
+    Render,
+    /// Print the flag or version values of each environment directory
+    Matrix(report::MatrixArgs),
+    /// Report environment directories that pin an older version than their parent
+    Downgrades,
+    /// Promote a component version to the paths matching a pattern in versions.yml
+    Promote(promote::PromoteArgs),
+}
+
+/// The sources of an ev2 directory.
+struct Project {
+    ev2_path: Utf8PathBuf,
+    environments_path: Utf8PathBuf,
+    scratch_path: Utf8PathBuf,
+    /// file name of the rendered config in each scratch directory
+    output: String,
+    format: OutputFormat,
+    layers: Vec<Layer>,
+    templates: manifest::Templates,
+    /// flags, versions and the matrices of the manifest
+    matrices: Vec<flags::Matrix>,
+    includes: includes::Includes,
+    /// patterns of the environment directories with their options
+    paths: Vec<(patterns::PathPattern, manifest::PathConfig)>,
+    /// environment variables, then `--set-file` and `--set` values
+    overrides: Vec<overrides::Override>,
+    loader: yaml::Loader,
+    /// yml files relative to the environments directory, grouped by directory
+    dirs_files: BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+    /// virtual environment directories expanded from generators, see [`generators::GENERATOR`]
+    generated: BTreeMap<Utf8PathBuf, generators::Instance>,
+    /// the files of the active profiles by environment directory, see [`profiles::files`]
+    profile_files: BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+    profiles: Vec<String>,
+    /// the `*.local.yml` files and `local/` tree by environment directory, merged last
+    local_files: BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+    /// the directories whose layers are merged for each environment directory, see [`Project::stack`]
+    stacks: BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+}
+
+impl Project {
+    fn load(cli: &Cli) -> Result<Self> {
+        Self::load_with_env(cli, &std::env::vars().collect())
+    }
+
+    /// Loads a project with the given environment variables, such as `CI`.
+    fn load_with_env(cli: &Cli, env: &HashMap<String, String>) -> Result<Self> {
+        let ev2_path = Utf8PathBuf::from_str(&cli.ev2)?;
+        let manifest = manifest::Manifest::load(
+            &ev2_path.join(cli.manifest.as_deref().unwrap_or("configur.yml")),
+            cli.manifest.is_some(),
+        )?;
+        let environments = (cli.environments.as_ref())
+            .or(manifest.environments.as_ref())
+            .map_or("environments", |e| e.as_str());
+        let environments_path = ev2_path.join(environments);
+        let scratch = (cli.scratch.as_ref())
+            .or(manifest.scratch.as_ref())
+            .map_or("scratch", |s| s.as_str());
+        let scratch_path = if cli.profile.is_empty() {
+            ev2_path.join(scratch)
+        } else {
+            let scratch = scratch.trim_end_matches('/');
+            ev2_path.join(format!("{scratch}@{}", cli.profile.join("+")))
+        };
+
+        let mut matrices = vec![
+            load_matrix(
+                &ev2_path,
+                manifest::FLAGS,
+                manifest::sources(&cli.flags, &manifest.flags),
+                None,
+                false,
+            )?,
+            load_matrix(
+                &ev2_path,
+                manifest::VERSIONS,
+                manifest::sources(&cli.versions, &manifest.versions),
+                None,
+                true,
+            )?,
+        ];
+        for matrix in &manifest.matrices {
+            matrices.push(load_matrix(
+                &ev2_path,
+                &matrix.name,
+                matrix.files.as_deref(),
+                matrix.at.as_deref(),
+                matrix.versions,
+            )?);
+        }
+        let mut includes = includes::Includes::default();
+        let include_sources = manifest::sources(&cli.include, &manifest.include);
+        for yml in config_paths(&ev2_path, include_sources, "include.yml")? {
+            includes.extend(includes::load_includes(&ev2_path, &yml)?);
+        }
+        let mut paths = Vec::new();
+        for path in &manifest.paths {
+            paths.push((patterns::PathPattern::parse(&path.pattern)?, path.clone()));
+        }
+        let mut overrides = overrides::Override::from_env(env.clone().into_iter())?;
+        for set_file in &cli.set_file {
+            overrides.push(overrides::Override::parse_set_file(set_file)?);
+        }
+        for set in &cli.set {
+            overrides.push(overrides::Override::parse_set(set)?);
+        }
+        let anchors = yaml::Anchors::load(&ev2_path.join("anchors"))?;
+        let loader = yaml::Loader::new(&ev2_path, anchors);
+
+        let environments_yml_paths = list_yml_paths(&environments_path);
+        let ignore = manifest.ignore()?;
+        let yml_files = environments_yml_paths
+            .iter()
+            .map(|x| {
+                x.strip_prefix(&environments_path)
+                    .with_context(|| "strip prefix")
+            })
+            .filter(|x| !x.as_ref().is_ok_and(|x| ignore.is_match(x)))
+            .collect::<Result<Vec<_>>>()?;
+        let overlays_path = ev2_path.join(manifest.overlays.as_deref().unwrap_or("overlays"));
+        let profile_names = profiles::names(&manifest.profiles, &overlays_path)?;
+        let (profile_ymls, yml_files): (Vec<_>, Vec<_>) = yml_files
+            .into_iter()
+            .partition(|file| profiles::profile_of(file, &profile_names).is_some());
+        let profile_files = profiles::files(
+            &cli.profile,
+            &profile_names,
+            &environments_path,
+            &profile_ymls,
+            &overlays_path,
+        )?;
+        let local = [profiles::LOCAL.to_string()];
+        let (local_ymls, yml_files): (Vec<_>, Vec<_>) = yml_files
+            .into_iter()
+            .partition(|file| profiles::profile_of(file, &local).is_some());
+        let in_ci = env
+            .get("CI")
+            .is_some_and(|ci| !ci.is_empty() && ci != "false");
+        let local_files = profiles::files(
+            if cli.no_local || in_ci { &[] } else { &local },
+            &local,
+            &environments_path,
+            &local_ymls,
+            &ev2_path,
+        )?;
+        for yml_path in local_files.values().flatten() {
+            eprintln!("local override: {yml_path}");
+        }
+        let (generator_ymls, yml_files): (Vec<_>, Vec<_>) = yml_files
+            .into_iter()
+            .partition(|file| file.file_name() == Some(generators::GENERATOR));
+        let dirs_files: BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>> = group_yml_files_by_dir(yml_files)
+            .into_iter()
+            .map(|(k, v)| (k, v.into_iter().map(|f| f.to_path_buf()).collect()))
+            .collect();
+        let extends = load_extends(&environments_path, &loader, &dirs_files)?;
+        let mut generated = BTreeMap::new();
+        let mut generator_dirs = BTreeSet::new();
+        for yml in generator_ymls {
+            let yml_path = environments_path.join(yml);
+            let generator_dir = yml.parent().unwrap_or(Utf8Path::new(""));
+            for instance in generators::parse_generator(&yml_path, loader.read_yml(&yml_path)?)? {
+                let dir = generator_dir.join(&instance.name);
+                if dirs_files.contains_key(&dir) {
+                    bail!("{yml_path} generates {dir} which is already an environment directory");
+                }
+                generated.insert(dir, instance);
+            }
+            generator_dirs.insert(generator_dir);
+        }
+        let mut stacks = BTreeMap::new();
+        for dir in dirs_files
+            .keys()
+            .filter(|dir| !generator_dirs.contains(dir.as_path()))
+            .chain(generated.keys())
+        {
+            stacks.insert(dir.clone(), stack(dir, &extends, &mut Vec::new())?);
+        }
+
+        let format = cli.format.or(manifest.format).unwrap_or_default();
+        let project = Project {
+            ev2_path,
+            environments_path,
+            scratch_path,
+            output: manifest::output(manifest.output.as_deref(), format)?,
+            format,
+            layers: manifest.layers(),
+            templates: manifest.templates,
+            matrices,
+            includes,
+            paths,
+            overrides,
+            loader,
+            dirs_files,
+            generated,
+            profile_files,
+            profiles: cli.profile.clone(),
+            local_files,
+            stacks,
+        };
+        let ancestor_paths = project.ancestor_paths();
+        check_paths(&project)?;
+        check_overlay_files(&project, &project.profile_files, "profile")?;
+        check_overlay_files(&project, &project.local_files, "local override")?;
+        check_targets(&project, &ancestor_paths)?;
+        check_conflicts(
+            &project.matrices,
+            &ancestor_paths,
+            cli.deny_conflicts || manifest.deny_conflicts,
+        )?;
+        Ok(project)
+    }
+
+    fn matrix(&self, name: &str) -> Result<&flags::Matrix> {
+        self.matrices
+            .iter()
+            .find(|matrix| matrix.name == name)
+            .ok_or_else(|| anyhow!("unknown matrix {name}"))
+    }
+
+    /// The captures of the first path pattern matching an environment directory.
+    fn path_captures(
+        &self,
+        dir: &Utf8Path,
+    ) -> Option<(
+        &manifest::PathConfig,
+        serde_json::Map<String, serde_json::Value>,
+    )> {
+        self.paths.iter().find_map(|(pattern, config)| {
+            pattern
+                .captures(dir.as_str())
+                .map(|captures| (config, captures))
+        })
+    }
+
+    /// The environment directories to render, relative to the environments directory:
+    /// the directories with yml files except generators, and the generated directories.
+    fn dirs(&self) -> impl Iterator<Item = &Utf8Path> {
+        self.stacks.keys().map(|dir| dir.as_path())
+    }
+
+    /// The directories whose layers are merged for an environment directory, with their target
+    /// paths: its ancestors from the root down, each preceded by the directories it extends.
+    fn stack(&self, dir: &Utf8Path) -> Vec<(&Utf8Path, String)> {
+        self.stacks[dir]
+            .iter()
+            .map(|dir| (dir.as_path(), self.ancestor_path(dir)))
+            .collect()
+    }
+
+    fn ancestor_path(&self, ancestor: &Utf8Path) -> String {
+        ancestor_path(&self.environments_path, ancestor, &self.ev2_path)
+    }
+
+    /// The target paths of every directory in the stack of every environment directory.
+    fn ancestor_paths(&self) -> BTreeSet<String> {
+        self.stacks
+            .values()
+            .flatten()
+            .map(|ancestor| self.ancestor_path(ancestor))
+            .collect()
+    }
+}
+
+/// The directories each environment directory extends with a top level `extends:` key.
+fn load_extends(
+    environments_path: &Utf8Path,
+    loader: &yaml::Loader,
+    dirs_files: &BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+) -> Result<BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>> {
+    let mut extends = BTreeMap::new();
+    for (dir, files) in dirs_files {
+        for file in files {
+            let yml_path = environments_path.join(file);
+            let json = loader.read_yml(&yml_path)?;
+            let Some(value) = json.get(EXTENDS) else {
+                continue;
+            };
+            let targets = match value {
+                serde_json::Value::String(target) => vec![target.as_str()],
+                serde_json::Value::Array(targets) => targets
+                    .iter()
+                    .map(|target| target.as_str())
+                    .collect::<Option<Vec<_>>>()
+                    .with_context(|| format!("expected directories for extends in {yml_path}"))?,
+                _ => bail!("expected directory or list of directories for extends in {yml_path}"),
+            };
+            for target in targets {
+                let target = Utf8Path::new(target.trim_matches('/'));
+                if !environments_path.join(target).is_dir() {
+                    bail!("{yml_path} extends unknown environment directory {target}");
+                }
+                let dir_extends: &mut Vec<Utf8PathBuf> = extends.entry(dir.clone()).or_default();
+                dir_extends.push(target.to_path_buf());
+            }
+        }
+    }
+    Ok(extends)
+}
+
+/// The stack of a directory: the stack of its parent, the stacks of the directories it
+/// extends and the directory itself, keeping the first of any repeated directories.
+fn stack(
+    dir: &Utf8Path,
+    extends: &BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+    visiting: &mut Vec<Utf8PathBuf>,
+) -> Result<Vec<Utf8PathBuf>> {
+    if let Some(start) = visiting.iter().position(|v| v == dir) {
+        let cycle = visiting[start..].iter().map(|v| v.as_str());
+        bail!(
+            "extends cycle: {} -> {dir}",
+            cycle.collect::<Vec<_>>().join(" -> ")
+        );
+    }
+    visiting.push(dir.to_path_buf());
+    let mut dirs = match dir.parent() {
+        Some(parent) => stack(parent, extends, visiting)?,
+        None => Vec::new(),
+    };
+    for target in extends.get(dir).into_iter().flatten() {
+        for target_dir in stack(target, extends, visiting)? {
+            if !dirs.contains(&target_dir) {
+                dirs.push(target_dir);
+            }
+        }
+    }
+    if !dirs.iter().any(|d| d == dir) {
+        dirs.push(dir.to_path_buf());
+    }
+    visiting.pop();
+    Ok(dirs)
+}
+
+/// The files matching the given paths or globs, or the default file if none are given and it exists.
+fn config_paths(
+    ev2_path: &Utf8Path,
+    patterns: Option<&[String]>,
+    default: &str,
+) -> Result<Vec<Utf8PathBuf>> {
+    let Some(patterns) = patterns else {
+        let path = ev2_path.join(default);
+        return Ok(if path.is_file() {
+            vec![path]
+        } else {
+            Vec::new()
+        });
+    };
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        let mut matched = glob(ev2_path.join(pattern).as_str())
+            .with_context(|| format!("invalid glob {pattern}"))?
+            .flatten()
+            .filter(|path| path.is_file())
+            .map(Utf8PathBuf::from_path_buf)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|path| anyhow!("non UTF-8 path {}", path.display()))?;
+        if matched.is_empty() {
+            bail!("{pattern} does not match any files in {ev2_path}");
+        }
+        matched.sort();
+        for path in matched {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Loads the files of a matrix like `flags.yml`, mounted at a JSON pointer.
+fn load_matrix(
+    ev2_path: &Utf8Path,
+    name: &str,
+    sources: Option<&[String]>,
+    at: Option<&str>,
+    semver: bool,
+) -> Result<flags::Matrix> {
+    let mut flags = flags::Flags::default();
+    for yml in config_paths(ev2_path, sources, &format!("{name}.yml"))? {
+        flags.extend(if semver {
+            versions::load_versions(&yml)?
+        } else {
+            flags::load_flags(&yml)?
+        });
+    }
+    let prefix = match at {
+        Some(at) => includes::parse_pointer(at)
+            .map_err(|err| anyhow!(err))
+            .with_context(|| format!("mounting matrix {name}"))?,
+        None => Vec::new(),
+    };
+    Ok(flags::Matrix {
+        name: name.to_string(),
+        flags,
+        prefix,
+    })
 }
 
 fn list_yml_paths(dir: &Utf8Path) -> Vec<Utf8PathBuf> {
@@ -70,187 +533,395 @@ fn ancestor_path(environments_path: &Utf8Path, ancestor: &Utf8Path, ev2_path: &U
 }
 
 fn main() -> Result<()> {
-    let Cli {
-        ev2,
-        environments,
-        scratch,
-        verbose,
-    } = &Cli::parse();
-
-    let ev2_path = Utf8PathBuf::from_str(ev2)?;
-    let environments_path = ev2_path.join(environments);
-    let scratch_path = ev2_path.join(scratch);
-
-    let flags = load_flags(&ev2_path.join("flags.yml"))?;
-    let versions = load_flags(&ev2_path.join("versions.yml"))?;
-    let includes = load_includes(&ev2_path)?;
-
-    let environments_yml_paths = list_yml_paths(&environments_path);
-    let yml_files = environments_yml_paths
-        .iter()
-        .map(|x| {
-            x.strip_prefix(&environments_path)
-                .with_context(|| "strip prefix")
-        })
-        .collect::<Result<Vec<_>>>()?;
-    let dirs_files = group_yml_files_by_dir(yml_files);
-    let dirs_files: BTreeMap<&Utf8Path, Vec<&Utf8Path>> = dirs_files
-        .iter()
-        .map(|(k, v)| (k.as_path(), v.clone()))
-        .collect();
+    let cli = Cli::parse();
+    let project = Project::load(&cli)?;
+    match &cli.command {
+        None | Some(Command::Render) => render(&project, cli.verbose, cli.provenance),
+        Some(Command::Matrix(args)) => report::matrix(&project, args),
+        Some(Command::Downgrades) => versions::report_downgrades(&project),
+        Some(Command::Promote(args)) => promote::promote(&project, args),
+    }
+}
 
-    let mut json_cache = JsonCache::new();
+/// A source merged into the config of an environment directory.
+#[derive(Serialize)]
+struct Provenance {
+    layer: String,
+    source: String,
+    /// the target path it was merged for
+    ancestor: String,
+}
 
-    let dirs = dirs_files.keys();
-    for dir in dirs {
-        let dump_json_path = scratch_path.join(dir).join("dump2.json");
-        println!("dump_json_path: {dump_json_path}");
-        let mut dump_json = json!({});
+/// The merged config of an environment directory before rendering.
+struct Merged {
+    json: serde_json::Value,
+    provenance: Vec<Provenance>,
+    inherited: jinga::Inherited,
+}
 
-        let mut ancestors = dir.ancestors().into_iter().collect::<Vec<_>>();
-        ancestors.reverse();
+fn render(project: &Project, verbose: bool, write_provenance: bool) -> Result<()> {
+    let mut json_cache = JsonCache::new();
 
-        for ancestor in &ancestors {
-            let ancestor_path = &ancestor_path(&environments_path, ancestor, &ev2_path);
+    let mut merged = BTreeMap::new();
+    for dir in project.dirs() {
+        merged.insert(dir, merge_dir(project, dir, &mut json_cache)?);
+    }
 
-            // add includes
-            if let Some(yml_paths) = includes.get(ancestor_path) {
-                for yml_path in yml_paths {
-                    dump_json = merge_yml(dump_json, &mut json_cache, yml_path)?;
-                }
+    // render the environment directories referenced with environment() first
+    let mut references = BTreeMap::new();
+    for (dir, m) in &merged {
+        let refs = jinga::environment_refs(&m.json);
+        for reference in &refs {
+            if !merged.contains_key(Utf8Path::new(reference)) {
+                bail!("unknown environment directory {reference} referenced in {dir}");
             }
+        }
+        references.insert(*dir, refs);
+    }
+    let mut rendered: BTreeMap<String, serde_json::Value> = BTreeMap::new();
+    for dir in render_order(&references)? {
+        let Merged {
+            json: mut dump_json,
+            provenance,
+            inherited,
+        } = merged.remove(dir).unwrap();
+        let dump_json_path = project.scratch_path.join(dir).join(&project.output);
+        println!("dump_json_path: {dump_json_path}");
 
-            // add flags & versions
-            if let Some(json) = flags.get(ancestor_path) {
-                dump_json = dump_json.merged_recursive::<Dfs>(json);
+        if project.templates.render {
+            let mut ctx = serde_json::Map::new();
+            if let Some((config, captures)) = project.path_captures(dir) {
+                if config.vars {
+                    ctx.extend(captures);
+                }
             }
-            if let Some(json) = versions.get(ancestor_path) {
-                dump_json = dump_json.merged_recursive::<Dfs>(json);
+            if let Some(instance) = project.generated.get(dir) {
+                ctx.extend(instance.params.clone());
             }
-
-            // add environments
-            if let Some(dir_files) = dirs_files.get(ancestor) {
-                for file in dir_files {
-                    let yml_path = environments_path.join(file);
-                    dump_json = merge_yml(dump_json, &mut json_cache, &yml_path)?;
+            ctx.insert(
+                "configur".to_string(),
+                configur_ctx(project, dir, &provenance),
+            );
+            let ctx = serde_json::Value::Object(ctx);
+            let environments = references[dir]
+                .iter()
+                .map(|reference| (reference.clone(), rendered[reference].clone()))
+                .collect();
+            match jinga::render(&mut dump_json, &ctx, &inherited, environments) {
+                Ok(_) => {}
+                Err(err) if project.templates.strict => {
+                    return Err(err.context(format!("rendering {dump_json_path}")));
                 }
-            }
-        }
-
-        dump_json.sort_keys_recursive::<Dfs>();
-
-        match jinga::render(&mut dump_json) {
-            Ok(_) => {}
-            Err(err) => {
-                if *verbose {
-                    println!("render error: {err}");
+                Err(err) => {
+                    if verbose {
+                        println!("render error: {err}");
+                    }
                 }
             }
         }
 
-        let dir = dump_json_path
+        let dir_path = dump_json_path
             .parent()
             .with_context(|| "parent of {dump_json_path}")?;
-        if !dir.exists() {
-            fs::create_dir_all(dir).with_context(|| format!("creating {dir}"))?;
+        if !dir_path.exists() {
+            fs::create_dir_all(dir_path).with_context(|| format!("creating {dir_path}"))?;
         }
-        fs::write(&dump_json_path, serde_json::to_string_pretty(&dump_json)?)
-            .with_context(|| format!("writing {dump_json_path}"))?;
+        let output = match project.format {
+            OutputFormat::Json => serde_json::to_string_pretty(&dump_json)?,
+            OutputFormat::Yaml => serde_yaml::to_string(&dump_json)?,
+        };
+        fs::write(&dump_json_path, output).with_context(|| format!("writing {dump_json_path}"))?;
+        if verbose {
+            for p in &provenance {
+                println!("  {} {} ({})", p.layer, p.source, p.ancestor);
+            }
+        }
+        if write_provenance {
+            let provenance_path = dir_path.join("provenance.json");
+            fs::write(&provenance_path, serde_json::to_string_pretty(&provenance)?)
+                .with_context(|| format!("writing {provenance_path}"))?;
+        }
+        rendered.insert(dir.to_string(), dump_json);
     }
     Ok(())
 }
 
-fn merge_yml(
-    dump_json: serde_json::Value,
-    json_cache: &mut JsonCache,
-    yml_path: &Utf8Path,
-) -> Result<serde_json::Value> {
-    Ok(if let Some(json) = json_cache.get(yml_path) {
-        dump_json.merged_recursive::<Dfs>(json)
-    } else {
-        let mut json: serde_json::Value = serde_yaml::from_slice(
-            &fs::read(yml_path).with_context(|| format!("reading file {yml_path}"))?,
-        )
-        .with_context(|| format!("reading yml {yml_path}"))?;
-        remove_brackets(&mut json)?;
-        let value = dump_json.merged_recursive::<Dfs>(&json);
-        json_cache.insert(yml_path.to_path_buf(), json);
-        value
-    })
-}
+/// Merges the layers of an environment directory from its root ancestor down, then the overrides.
+fn merge_dir(project: &Project, dir: &Utf8Path, json_cache: &mut JsonCache) -> Result<Merged> {
+    let mut dump_json = json!({});
+    let mut provenance = Vec::new();
+    let mut inherited = jinga::Inherited::new();
+
+    if let Some((config, captures)) = project.path_captures(dir) {
+        if let Some(at) = &config.at {
+            let prefix = includes::parse_pointer(at).map_err(|err| anyhow!(err))?;
+            let json = includes::nest(&prefix, serde_json::Value::Object(captures));
+            dump_json = merge(dump_json, &json, &mut inherited);
+            provenance.push(Provenance {
+                layer: "paths".to_string(),
+                source: config.pattern.clone(),
+                ancestor: project.ancestor_path(dir),
+            });
+        }
+    }
 
-fn load_flags(yml: &Utf8Path) -> Result<HashMap<String, serde_json::Value>> {
-    let mut flags = HashMap::new();
-    let mut json: serde_json::Value = serde_yaml::from_slice(&fs::read(yml)?)?;
-    remove_brackets(&mut json)?;
-    for (key, values) in json.as_object().unwrap() {
-        for (value, paths) in values.as_object().unwrap() {
-            for path in paths.as_array().unwrap() {
-                let path = path.as_str().unwrap();
-                if flags.contains_key(path) {
-                    let pairs: &mut BTreeMap<String, String> = flags.get_mut(path).unwrap();
-                    pairs.insert(key.to_string(), value.to_string());
-                } else {
-                    let mut pairs = BTreeMap::new();
-                    pairs.insert(key.to_string(), value.to_string());
-                    flags.insert(path.to_string(), pairs);
+    for (ancestor, ancestor_path) in &project.stack(dir) {
+        if !dir.starts_with(ancestor) {
+            provenance.push(Provenance {
+                layer: EXTENDS.to_string(),
+                source: ancestor_path.clone(),
+                ancestor: project.ancestor_path(dir),
+            });
+        }
+        for layer in &project.layers {
+            let mut add_provenance = |source: &str| {
+                provenance.push(Provenance {
+                    layer: layer.to_string(),
+                    source: source.to_string(),
+                    ancestor: ancestor_path.clone(),
+                })
+            };
+            match layer {
+                Layer::Includes => {
+                    for include in project.includes.get(ancestor_path) {
+                        add_provenance(include.yml_path.as_str());
+                        let mut json =
+                            read_yml(json_cache, &project.loader, &include.yml_path)?.clone();
+                        if !include.params.is_empty() {
+                            jinga::render_params(&mut json, &include.params).with_context(
+                                || format!("rendering {} with params", include.yml_path),
+                            )?;
+                        }
+                        dump_json = merge(dump_json, &include.nest(json), &mut inherited);
+                    }
+                }
+                Layer::Matrix(name) => {
+                    let matrix = project.matrix(name)?;
+                    if let Some(json) = matrix.get(ancestor_path)? {
+                        let ymls = matrix.flags.ymls().iter().map(|yml| yml.as_str());
+                        add_provenance(&ymls.collect::<Vec<_>>().join(", "));
+                        dump_json = merge(dump_json, &json, &mut inherited);
+                    }
+                }
+                Layer::Environments => {
+                    if let Some(dir_files) = project.dirs_files.get(*ancestor) {
+                        for file in dir_files {
+                            let yml_path = project.environments_path.join(file);
+                            add_provenance(yml_path.as_str());
+                            let json = read_yml(json_cache, &project.loader, &yml_path)?;
+                            if json.get(EXTENDS).is_some() {
+                                let mut json = json.clone();
+                                json.as_object_mut().unwrap().remove(EXTENDS);
+                                dump_json = merge(dump_json, &json, &mut inherited);
+                            } else {
+                                dump_json = merge(dump_json, json, &mut inherited);
+                            }
+                        }
+                    }
+                    if let Some(instance) = project.generated.get(*ancestor) {
+                        add_provenance(&instance.source());
+                        let json = serde_json::Value::Object(instance.params.clone());
+                        dump_json = merge(dump_json, &json, &mut inherited);
+                    }
+                    for yml_path in project.profile_files.get(*ancestor).into_iter().flatten() {
+                        add_provenance(yml_path.as_str());
+                        let json = read_yml(json_cache, &project.loader, yml_path)?;
+                        dump_json = merge(dump_json, json, &mut inherited);
+                    }
                 }
             }
         }
+        for yml_path in project.local_files.get(*ancestor).into_iter().flatten() {
+            provenance.push(Provenance {
+                layer: profiles::LOCAL.to_string(),
+                source: yml_path.to_string(),
+                ancestor: ancestor_path.clone(),
+            });
+            let json = read_yml(json_cache, &project.loader, yml_path)?;
+            dump_json = merge(dump_json, json, &mut inherited);
+        }
     }
-    // convert values to json
-    let flags = flags
-        .into_iter()
-        .map(|(path, pairs)| {
-            let mut map = serde_json::Map::new();
-            pairs.into_iter().for_each(|(key, value)| {
-                let value = match value.as_str() {
-                    "true" => json!(true),
-                    "false" => json!(false),
-                    _ => json!(value),
-                };
-                map.insert(key, value);
+
+    let dir_path = project.ancestor_path(dir);
+    for set in &project.overrides {
+        if set.applies_to(&dir_path) {
+            set.apply(&mut dump_json)?;
+            provenance.push(Provenance {
+                layer: "set".to_string(),
+                source: set.source.clone(),
+                ancestor: dir_path.clone(),
             });
-            (path, serde_json::Value::Object(map))
-        })
-        .collect();
-    Ok(flags)
+        }
+    }
+
+    dump_json.sort_keys_recursive::<Dfs>();
+    Ok(Merged {
+        json: dump_json,
+        provenance,
+        inherited,
+    })
 }
 
-fn load_includes(ev2_path: &Utf8Path) -> Result<HashMap<String, Vec<Utf8PathBuf>>> {
-    let include_yml = ev2_path.join("include.yml");
-    let mut json: serde_json::Value = serde_yaml::from_slice(
-        &fs::read(&include_yml).with_context(|| format!("reading file {include_yml}"))?,
-    )?;
-    remove_brackets(&mut json)?;
-    let mut include_paths = HashMap::new();
-    for (key, values) in json.as_object().unwrap() {
-        let values = values
-            .as_array()
-            .unwrap()
+/// Orders environment directories so that those referenced come first,
+/// failing on reference cycles.
+fn render_order<'a>(
+    references: &BTreeMap<&'a Utf8Path, BTreeSet<String>>,
+) -> Result<Vec<&'a Utf8Path>> {
+    let mut remaining = references.clone();
+    let mut order: Vec<&Utf8Path> = Vec::new();
+    while !remaining.is_empty() {
+        let ready = remaining
             .iter()
-            .map(|v| v.as_str().unwrap())
+            .filter(|(_, refs)| refs.iter().all(|r| order.iter().any(|dir| *dir == r)))
+            .map(|(dir, _)| *dir)
             .collect::<Vec<_>>();
-        include_paths.insert(key, values);
+        if ready.is_empty() {
+            // follow unrendered references until a directory repeats
+            let mut path: Vec<&Utf8Path> = Vec::new();
+            let mut dir = *remaining.keys().next().unwrap();
+            while !path.contains(&dir) {
+                path.push(dir);
+                dir = remaining[&dir]
+                    .iter()
+                    .find_map(|r| remaining.keys().find(|d| **d == r))
+                    .copied()
+                    .unwrap();
+            }
+            let start = path.iter().position(|d| *d == dir).unwrap();
+            let cycle = path[start..].iter().chain([&dir]).map(|d| d.as_str());
+            bail!(
+                "environment() references form a cycle: {}",
+                cycle.collect::<Vec<_>>().join(" -> ")
+            );
+        }
+        for dir in ready {
+            remaining.remove(dir);
+            order.push(dir);
+        }
     }
+    Ok(order)
+}
 
-    let mut paths_cache: HashMap<Utf8PathBuf, Vec<Utf8PathBuf>> = HashMap::new();
-    let mut includes = HashMap::new();
-    for (key, values) in include_paths {
-        let mut combined_paths = Vec::new();
-        for value in values {
-            let include_path = ev2_path.join(value);
-            if let Some(paths) = paths_cache.get(&include_path) {
-                combined_paths.extend(paths.clone());
-            } else {
-                let paths = list_yml_paths(&include_path);
-                combined_paths.extend(paths.clone());
-                paths_cache.insert(include_path, paths);
-            }
+/// The `configur` template namespace, describing the environment directory being rendered.
+fn configur_ctx(project: &Project, dir: &Utf8Path, provenance: &[Provenance]) -> serde_json::Value {
+    let mut sources = Vec::new();
+    for p in provenance {
+        if !sources.contains(&p.source) {
+            sources.push(p.source.clone());
         }
-        includes.insert(key.to_string(), combined_paths);
     }
-    Ok(includes)
+    let vars = project
+        .path_captures(dir)
+        .map(|(_, captures)| captures)
+        .unwrap_or_default();
+    json!({
+        "dir": project.ancestor_path(dir),
+        "vars": vars,
+        "segments": dir.components().map(|c| c.as_str()).collect::<Vec<_>>(),
+        "dir_name": dir.file_name().unwrap_or_default(),
+        "ev2": project.ev2_path.as_str(),
+        "ancestors": project.stack(dir).into_iter().map(|(_, path)| path).collect::<Vec<_>>(),
+        "sources": sources,
+        "profiles": project.profiles,
+    })
+}
+
+/// Checks that every environment directory matches a path pattern, if there are any.
+fn check_paths(project: &Project) -> Result<()> {
+    if project.paths.is_empty() {
+        return Ok(());
+    }
+    let unmatched = project
+        .dirs()
+        .filter(|dir| project.path_captures(dir).is_none())
+        .map(|dir| project.ancestor_path(dir))
+        .collect::<Vec<_>>();
+    if !unmatched.is_empty() {
+        let patterns = project.paths.iter().map(|(p, _)| p.pattern.as_str());
+        bail!(
+            "environment directories matching no path pattern of {}: {}",
+            patterns.collect::<Vec<_>>().join(", "),
+            unmatched.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Checks that profile or local files are in environment directories or their ancestors.
+fn check_overlay_files(
+    project: &Project,
+    files: &BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+    kind: &str,
+) -> Result<()> {
+    let dirs = project.stacks.values().flatten().collect::<BTreeSet<_>>();
+    let unknown = files
+        .iter()
+        .filter(|(dir, _)| !dirs.contains(dir))
+        .flat_map(|(_, files)| files.iter().map(|file| file.as_str()))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        bail!(
+            "{kind} files outside of the environment directories: {}",
+            unknown.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Checks that every target path matches an environment directory or one of its ancestors.
+fn check_targets(project: &Project, ancestor_paths: &BTreeSet<String>) -> Result<()> {
+    let mut unknown = project.includes.unknown_targets(ancestor_paths);
+    for matrix in &project.matrices {
+        unknown.extend(matrix.flags.unknown_targets(ancestor_paths));
+    }
+    if !unknown.is_empty() {
+        return Err(anyhow!("{}", unknown.join("\n")));
+    }
+    Ok(())
+}
+
+fn check_conflicts(
+    matrices: &[flags::Matrix],
+    ancestor_paths: &BTreeSet<String>,
+    deny_conflicts: bool,
+) -> Result<()> {
+    let conflicts = ancestor_paths
+        .iter()
+        .flat_map(|path| matrices.iter().flat_map(|m| m.flags.conflicts(path)))
+        .map(|conflict| conflict.to_string())
+        .collect::<Vec<_>>();
+    if deny_conflicts && !conflicts.is_empty() {
+        return Err(anyhow!("{}", conflicts.join("\n")));
+    }
+    for conflict in conflicts {
+        eprintln!("warning: {conflict}");
+    }
+    Ok(())
+}
+
+/// Merges a layer, keeping the values that templates calling `inherited()` override.
+fn merge(
+    mut dump_json: serde_json::Value,
+    json: &serde_json::Value,
+    inherited: &mut jinga::Inherited,
+) -> serde_json::Value {
+    jinga::take_inherited(&mut dump_json, json, "", inherited);
+    dump_json.merged_recursive::<Dfs>(json)
+}
+
+fn read_yml<'c>(
+    json_cache: &'c mut JsonCache,
+    loader: &yaml::Loader,
+    yml_path: &Utf8Path,
+) -> Result<&'c serde_json::Value> {
+    if !json_cache.contains_key(yml_path) {
+        let mut json = loader.read_yml(yml_path)?;
+        remove_brackets(&mut json)?;
+        json_cache.insert(yml_path.to_path_buf(), json);
+    }
+    json_cache
+        .get(yml_path)
+        .with_context(|| format!("reading {yml_path}"))
 }
 
 fn remove_brackets(value: &mut serde_json::Value) -> Result<()> {
@@ -296,4 +967,167 @@ pub mod test {
         assert_eq!(&value, &expected);
         Ok(())
     }
+
+    #[test]
+    fn test_load_matrix() -> Result<()> {
+        let ev2_path = yaml::test::temp_ev2(
+            "load_matrix",
+            &[("skus.yml", "vm: {D4s: [environments/prod]}")],
+        )?;
+        let matrix = load_matrix(&ev2_path, "skus", None, Some("/compute/skus"), false)?;
+        assert_eq!(
+            matrix.get("environments/prod")?,
+            Some(json!({"compute": {"skus": {"vm": "D4s"}}}))
+        );
+        assert!(load_matrix(&ev2_path, "quotas", None, None, false)?
+            .get("environments/prod")?
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_order() -> Result<()> {
+        let refs = |refs: &[&str]| refs.iter().map(|r| r.to_string()).collect::<BTreeSet<_>>();
+        let mut references = BTreeMap::from([
+            (Utf8Path::new("dev"), refs(&["prod/eastus"])),
+            (Utf8Path::new("prod/eastus"), refs(&["prod/westus"])),
+            (Utf8Path::new("prod/westus"), refs(&[])),
+        ]);
+        assert_eq!(
+            render_order(&references)?,
+            vec!["prod/westus", "prod/eastus", "dev"]
+        );
+        // dev only references the cycle
+        references.insert(Utf8Path::new("prod/westus"), refs(&["prod/eastus"]));
+        assert_eq!(
+            render_order(&references).unwrap_err().to_string(),
+            "environment() references form a cycle: prod/eastus -> prod/westus -> prod/eastus"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_files() -> Result<()> {
+        let ev2_path = yaml::test::temp_ev2(
+            "local_files",
+            &[
+                ("flags.yml", "replicas:\n  3: [environments/prod]\n"),
+                ("environments/prod/region.yml", "name: prod\nreplicas: 1"),
+                ("environments/prod/region.local.yml", "replicas: 5"),
+                ("environments/prod/westus/region.yml", "region: westus"),
+                ("local/prod/westus/debug.yml", "debug: true\nregion: mine"),
+            ],
+        )?;
+        let merged = |args: &[&str], env: &[(&str, &str)]| -> Result<Merged> {
+            let cli = Cli::parse_from(["configur", "--ev2", ev2_path.as_str()].iter().chain(args));
+            let env = env.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+            let project = Project::load_with_env(&cli, &env.collect())?;
+            merge_dir(
+                &project,
+                Utf8Path::new("prod/westus"),
+                &mut JsonCache::new(),
+            )
+        };
+
+        // merged after all the other layers of each directory
+        let local = merged(&[], &[("CI", "false")])?;
+        assert_eq!(
+            local.json,
+            json!({"name": "prod", "replicas": 5, "region": "mine", "debug": true})
+        );
+        let provenance = local
+            .provenance
+            .iter()
+            .map(|p| {
+                let source = Utf8Path::new(&p.source).strip_prefix(&ev2_path).unwrap();
+                format!("{} {source} ({})", p.layer, p.ancestor)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            provenance,
+            vec![
+                "flags flags.yml (environments/prod)",
+                "environments environments/prod/region.yml (environments/prod)",
+                "local environments/prod/region.local.yml (environments/prod)",
+                "environments environments/prod/westus/region.yml (environments/prod/westus)",
+                "local local/prod/westus/debug.yml (environments/prod/westus)",
+            ]
+        );
+
+        // skipped in CI and with --no-local
+        for skipped in [
+            merged(&[], &[("CI", "true")])?,
+            merged(&["--no-local"], &[])?,
+        ] {
+            assert_eq!(
+                skipped.json,
+                json!({"name": "prod", "replicas": 1, "region": "westus"})
+            );
+            assert!(skipped.provenance.iter().all(|p| p.layer != "local"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stack() -> Result<()> {
+        let path = |p: &str| Utf8PathBuf::from(p);
+        let mut extends = BTreeMap::from([
+            (path("prod/westus-dr"), vec![path("prod/westus")]),
+            (path("prod/westus"), vec![path("shared/network")]),
+        ]);
+        let stack = stack(Utf8Path::new("prod/westus-dr"), &extends, &mut Vec::new())?;
+        assert_eq!(
+            stack,
+            [
+                "",
+                "prod",
+                "shared",
+                "shared/network",
+                "prod/westus",
+                "prod/westus-dr"
+            ]
+            .map(path)
+        );
+        extends.insert(path("shared/network"), vec![path("prod/westus-dr")]);
+        let err =
+            super::stack(Utf8Path::new("prod/westus-dr"), &extends, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "extends cycle: prod/westus-dr -> prod/westus -> shared/network -> prod/westus-dr"
+        );
+        // directories extending a cycle are not part of it
+        extends.insert(path("dev"), vec![path("prod/westus")]);
+        let err = super::stack(Utf8Path::new("dev"), &extends, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "extends cycle: prod/westus -> shared/network -> prod/westus-dr -> prod/westus"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_paths() -> Result<()> {
+        let ev2_path = yaml::test::temp_ev2(
+            "config_paths",
+            &[
+                ("flags/b.yml", "{}"),
+                ("flags/a.yml", "{}"),
+                ("team.yml", "{}"),
+            ],
+        )?;
+        let paths = |patterns: &[&str]| -> Result<Vec<String>> {
+            let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            Ok(config_paths(&ev2_path, Some(&patterns), "flags.yml")?
+                .iter()
+                .map(|path| path.strip_prefix(&ev2_path).unwrap().to_string())
+                .collect())
+        };
+        assert!(config_paths(&ev2_path, None, "flags.yml")?.is_empty());
+        assert_eq!(
+            paths(&["flags/*.yml", "team.yml", "flags/a.yml"])?,
+            vec!["flags/a.yml", "flags/b.yml", "team.yml"]
+        );
+        assert!(paths(&["missing.yml"]).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,314 @@
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use camino::Utf8Path;
+use clap::ValueEnum;
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+
+/// The sources merged into the config of an environment directory.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub enum Layer {
+    Includes,
+    Environments,
+    /// a matrix file like `flags.yml` by name
+    Matrix(String),
+}
+
+impl From<String> for Layer {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "includes" => Layer::Includes,
+            "environments" => Layer::Environments,
+            _ => Layer::Matrix(name),
+        }
+    }
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::Includes => f.write_str("includes"),
+            Layer::Environments => f.write_str("environments"),
+            Layer::Matrix(name) => f.write_str(name),
+        }
+    }
+}
+
+/// The built in matrices.
+pub const FLAGS: &str = "flags";
+pub const VERSIONS: &str = "versions";
+
+/// A matrix file like `flags.yml`, mapping names to values to target paths.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixConfig {
+    pub name: String,
+    /// files or globs [default: `<name>.yml` if it exists]
+    pub files: Option<Vec<String>>,
+    /// JSON pointer to mount the values at
+    pub at: Option<String>,
+    /// whether the values are semantic versions
+    #[serde(default)]
+    pub versions: bool,
+}
+
+/// The format of the rendered config files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Templates {
+    /// whether to render jinja templates
+    pub render: bool,
+    /// fail on templates that do not render instead of leaving them as they are
+    pub strict: bool,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            render: true,
+            strict: false,
+        }
+    }
+}
+
+/// A pattern like `{cloud}/{stage}/{region}` for environment directories,
+/// relative to the environments directory.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathConfig {
+    pub pattern: String,
+    /// whether the captures are template variables
+    #[serde(default = "default_true")]
+    pub vars: bool,
+    /// JSON pointer to merge the captures at as keys, before the other layers
+    pub at: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// `configur.yml` at the ev2 root, describing the sources and outputs of a project.
+/// Command line options override it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// directory of environment directories
+    pub environments: Option<String>,
+    /// directory to render to
+    pub scratch: Option<String>,
+    /// file name of the rendered config in each scratch directory
+    pub output: Option<String>,
+    pub format: Option<OutputFormat>,
+    /// flags files or globs
+    pub flags: Option<Vec<String>>,
+    /// versions files or globs
+    pub versions: Option<Vec<String>>,
+    /// include files or globs
+    pub include: Option<Vec<String>>,
+    /// matrices besides flags and versions
+    pub matrices: Vec<MatrixConfig>,
+    /// patterns of the environment directories, the first match applies
+    pub paths: Vec<PathConfig>,
+    /// the layers to merge, from first to last
+    pub layers: Option<Vec<Layer>>,
+    pub deny_conflicts: bool,
+    pub templates: Templates,
+    /// globs of environment yml files to skip, relative to the environments directory
+    pub ignore: Vec<String>,
+    /// directory of overlay trees mirroring the environments directory, one per profile
+    pub overlays: Option<String>,
+    /// profile names besides the directories of the overlays directory
+    pub profiles: Vec<String>,
+}
+
+impl Manifest {
+    /// Loads a manifest, or the defaults if it does not exist and is not required.
+    pub fn load(yml: &Utf8Path, required: bool) -> Result<Self> {
+        if !required && !yml.exists() {
+            return Ok(Manifest::default());
+        }
+        let source = fs::read_to_string(yml).with_context(|| format!("reading file {yml}"))?;
+        let manifest =
+            parse_manifest(&source).with_context(|| format!("reading manifest {yml}"))?;
+        Ok(manifest)
+    }
+
+    /// The layers from first to last, defaulting to includes, flags, versions,
+    /// the other matrices in order and environments.
+    pub fn layers(&self) -> Vec<Layer> {
+        self.layers.clone().unwrap_or_else(|| {
+            let mut layers = vec![
+                Layer::Includes,
+                Layer::Matrix(FLAGS.to_string()),
+                Layer::Matrix(VERSIONS.to_string()),
+            ];
+            layers.extend(self.matrices.iter().map(|m| Layer::Matrix(m.name.clone())));
+            layers.push(Layer::Environments);
+            layers
+        })
+    }
+
+    pub fn ignore(&self) -> Result<GlobSet> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.ignore {
+            builder
+                .add(Glob::new(pattern).with_context(|| format!("invalid ignore glob {pattern}"))?);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+fn parse_manifest(source: &str) -> Result<Manifest> {
+    let manifest: Manifest = serde_yaml::from_str(source)?;
+    let mut names = vec![FLAGS, VERSIONS];
+    for matrix in &manifest.matrices {
+        let reserved = ["includes", "environments"];
+        if names.contains(&matrix.name.as_str()) || reserved.contains(&matrix.name.as_str()) {
+            bail!("matrix name {} is already used", matrix.name);
+        }
+        names.push(&matrix.name);
+    }
+    let layers = manifest.layers();
+    for (i, layer) in layers.iter().enumerate() {
+        if layers[..i].contains(layer) {
+            bail!("layer {layer} is listed more than once");
+        }
+        if let Layer::Matrix(name) = layer {
+            if !names.contains(&name.as_str()) {
+                bail!("unknown layer {name}, expected includes, environments or one of the matrices {names:?}");
+            }
+        }
+    }
+    Ok(manifest)
+}
+
+/// The file name of the rendered config in each scratch directory, defaulting to
+/// `dump2.json` or `dump2.yaml` by format.
+pub fn output(output: Option<&str>, format: OutputFormat) -> Result<String> {
+    let (name, default, other) = match format {
+        OutputFormat::Json => ("json", "dump2.json", [".yaml", ".yml"].as_slice()),
+        OutputFormat::Yaml => ("yaml", "dump2.yaml", [".json"].as_slice()),
+    };
+    match output {
+        None => Ok(default.to_string()),
+        Some(output) if other.iter().any(|ext| output.ends_with(ext)) => {
+            bail!("output {output} does not match format {name}")
+        }
+        Some(output) => Ok(output.to_string()),
+    }
+}
+
+/// The command line values if any, else the manifest values.
+pub fn sources<'a>(cli: &'a [String], manifest: &'a Option<Vec<String>>) -> Option<&'a [String]> {
+    if cli.is_empty() {
+        manifest.as_deref()
+    } else {
+        Some(cli)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let manifest = parse_manifest(
+            r#"
+environments: envs
+output: config.yaml
+format: yaml
+flags: [flags/*.yml]
+matrices:
+  - name: skus
+    at: /compute/skus
+layers: [environments, includes, skus]
+templates:
+  strict: true
+ignore: ["**/*.draft.yml"]
+profiles: [canary]
+"#,
+        )?;
+        assert_eq!(manifest.environments.as_deref(), Some("envs"));
+        assert_eq!(manifest.scratch, None);
+        assert_eq!(manifest.format, Some(OutputFormat::Yaml));
+        assert_eq!(
+            manifest.layers(),
+            vec![
+                Layer::Environments,
+                Layer::Includes,
+                Layer::Matrix("skus".into())
+            ]
+        );
+        assert_eq!(
+            parse_manifest("matrices: [{name: skus}]")?.layers(),
+            vec![
+                Layer::Includes,
+                Layer::Matrix("flags".into()),
+                Layer::Matrix("versions".into()),
+                Layer::Matrix("skus".into()),
+                Layer::Environments,
+            ]
+        );
+        assert!(manifest.templates.render);
+        assert!(manifest.templates.strict);
+        assert!(manifest.ignore()?.is_match("prod/region.draft.yml"));
+        assert_eq!(manifest.profiles, ["canary"]);
+
+        let err = parse_manifest("layers: [flags, flags]").unwrap_err();
+        assert_eq!(err.to_string(), "layer flags is listed more than once");
+        assert!(parse_manifest("envs: environments").is_err());
+        let err = parse_manifest("layers: [flags, quotas]").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"unknown layer quotas, expected includes, environments or one of the matrices ["flags", "versions"]"#
+        );
+        let err = parse_manifest("matrices: [{name: versions}]").unwrap_err();
+        assert_eq!(err.to_string(), "matrix name versions is already used");
+        Ok(())
+    }
+
+    #[test]
+    fn test_output() -> Result<()> {
+        assert_eq!(output(None, OutputFormat::Json)?, "dump2.json");
+        assert_eq!(output(None, OutputFormat::Yaml)?, "dump2.yaml");
+        assert_eq!(
+            output(Some("config.yml"), OutputFormat::Yaml)?,
+            "config.yml"
+        );
+        let err = output(Some("dump2.json"), OutputFormat::Yaml).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "output dump2.json does not match format yaml"
+        );
+        assert!(output(Some("config.yaml"), OutputFormat::Json).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sources() {
+        let manifest = Some(vec!["flags/*.yml".to_string()]);
+        assert_eq!(sources(&[], &None), None);
+        assert_eq!(
+            sources(&[], &manifest),
+            Some(&manifest.as_ref().unwrap()[..])
+        );
+        let cli = ["team.yml".to_string()];
+        assert_eq!(sources(&cli, &manifest), Some(&cli[..]));
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use std::fs;
+
+use crate::patterns::Targets;
+use crate::yaml::to_json;
+
+/// The prefix of environment variables like `CONFIGUR_SET__path__to__key=value`.
+const ENV_PREFIX: &str = "CONFIGUR_SET__";
+
+/// A value set from the command line or the environment, applied after all other layers.
+pub struct Override {
+    /// the environment directories it applies to, or all of them
+    targets: Option<Targets>,
+    keys: Vec<String>,
+    value: serde_json::Value,
+    /// the option or variable it came from
+    pub source: String,
+}
+
+impl Override {
+    /// Parses `[target patterns:]path.to.key=value`, with the value typed as YAML.
+    pub fn parse_set(set: &str) -> Result<Self> {
+        let (targets, keys, value) = split(set)?;
+        let value: serde_yaml::Value =
+            serde_yaml::from_str(value).with_context(|| format!("parsing value of --set {set}"))?;
+        Ok(Override {
+            targets,
+            keys,
+            value: to_json(value)?,
+            source: format!("--set {set}"),
+        })
+    }
+
+    /// Parses `[target patterns:]path.to.key=file`, with the contents of the file as a string.
+    pub fn parse_set_file(set_file: &str) -> Result<Self> {
+        let (targets, keys, path) = split(set_file)?;
+        let contents = fs::read_to_string(path).with_context(|| format!("reading file {path}"))?;
+        Ok(Override {
+            targets,
+            keys,
+            value: serde_json::Value::String(contents),
+            source: format!("--set-file {set_file}"),
+        })
+    }
+
+    /// Parses the `CONFIGUR_SET__path__to__key=value` variables, with the values typed as YAML.
+    pub fn from_env(vars: impl Iterator<Item = (String, String)>) -> Result<Vec<Self>> {
+        let mut overrides = Vec::new();
+        for (name, value) in vars {
+            let Some(keys) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let keys = keys
+                .split("__")
+                .map(|key| key.to_string())
+                .collect::<Vec<_>>();
+            if keys.iter().any(|key| key.is_empty()) {
+                bail!("invalid key path in environment variable {name}");
+            }
+            let yaml: serde_yaml::Value = serde_yaml::from_str(&value)
+                .with_context(|| format!("parsing environment variable {name}"))?;
+            overrides.push(Override {
+                targets: None,
+                keys,
+                value: to_json(yaml)?,
+                source: format!("${name}"),
+            });
+        }
+        overrides.sort_by(|a, b| a.source.cmp(&b.source));
+        Ok(overrides)
+    }
+
+    /// Whether it applies to the environment directory with a target path.
+    pub fn applies_to(&self, path: &str) -> bool {
+        match &self.targets {
+            Some(targets) => targets.matches(path).is_some(),
+            None => true,
+        }
+    }
+
+    /// Replaces the value at the key path, creating objects on the way.
+    /// Keys of arrays are indexes.
+    pub fn apply(&self, mut value: &mut serde_json::Value) -> Result<()> {
+        for key in &self.keys {
+            value = match value {
+                serde_json::Value::Array(array) => {
+                    let len = array.len();
+                    key.parse::<usize>()
+                        .ok()
+                        .and_then(|i| array.get_mut(i))
+                        .with_context(|| {
+                            format!("{}: no index {key} in array of {len}", self.source)
+                        })?
+                }
+                value => {
+                    if !value.is_object() {
+                        *value = serde_json::Value::Object(serde_json::Map::new());
+                    }
+                    value
+                        .as_object_mut()
+                        .unwrap()
+                        .entry(key.clone())
+                        .or_insert(serde_json::Value::Null)
+                }
+            };
+        }
+        *value = self.value.clone();
+        Ok(())
+    }
+}
+
+/// Splits `[target patterns:]path.to.key=value`.
+fn split(set: &str) -> Result<(Option<Targets>, Vec<String>, &str)> {
+    let Some((key_path, value)) = set.split_once('=') else {
+        bail!("expected [target patterns:]path.to.key=value, found {set}");
+    };
+    let (targets, key_path) = match key_path.split_once(':') {
+        Some((patterns, key_path)) => (Some(Targets::parse(patterns)?), key_path),
+        None => (None, key_path),
+    };
+    let keys = key_path
+        .split('.')
+        .map(|key| key.to_string())
+        .collect::<Vec<_>>();
+    if keys.iter().any(|key| key.is_empty()) {
+        bail!("invalid key path {key_path} in {set}");
+    }
+    Ok((targets, keys, value))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_set() -> Result<()> {
+        let mut value = json!({"service": {"replicas": 1, "zones": [1, 2]}, "name": "api"});
+        for set in [
+            "service.replicas=3",
+            "service.zones.1=3",
+            "service.debug=true",
+            "name.first=web",
+            "environments/prod/**:service.sku=P1",
+        ] {
+            let set = Override::parse_set(set)?;
+            if set.applies_to("environments/dev") {
+                set.apply(&mut value)?;
+            }
+        }
+        assert_eq!(
+            value,
+            json!({"service": {"replicas": 3, "zones": [1, 3], "debug": true}, "name": {"first": "web"}})
+        );
+        let err = Override::parse_set("service.zones.2=3")?
+            .apply(&mut value)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--set service.zones.2=3: no index 2 in array of 2"
+        );
+        assert!(Override::parse_set("service.replicas").is_err());
+        assert!(Override::parse_set("service..replicas=1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_env() -> Result<()> {
+        let vars = [
+            ("PATH", "/bin"),
+            ("CONFIGUR_SET__service__replicas", "3"),
+            ("CONFIGUR_SET__name", "api"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let overrides = Override::from_env(vars.into_iter())?;
+        let mut value = json!({});
+        for set in &overrides {
+            set.apply(&mut value)?;
+        }
+        assert_eq!(value, json!({"service": {"replicas": 3}, "name": "api"}));
+        assert_eq!(overrides[0].source, "$CONFIGUR_SET__name");
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use globset::Glob;
+use globset::GlobBuilder;
+use globset::GlobMatcher;
+use std::collections::BTreeSet;
+
+use crate::problems::did_you_mean;
+
+/// How specific a pattern is. Exact paths are more specific than globs and
+/// globs with more literal segments are more specific than those with fewer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity {
+    exact: bool,
+    literal_segments: usize,
+    literal_chars: usize,
+}
+
+struct Pattern {
+    pattern: String,
+    matcher: GlobMatcher,
+    specificity: Specificity,
+}
+
+/// The most specific pattern matching a path.
+#[derive(Clone, Copy, Debug)]
+pub struct Match<'a> {
+    pub specificity: Specificity,
+    pub pattern: &'a str,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Self> {
+        let glob: Glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid path pattern {pattern}"))?;
+        let is_literal = |s: &str| !s.contains(['*', '?', '[', '{']);
+        let specificity = Specificity {
+            exact: is_literal(pattern),
+            literal_segments: pattern.split('/').take_while(|s| is_literal(s)).count(),
+            literal_chars: pattern.chars().filter(|c| !"*?[]{},".contains(*c)).count(),
+        };
+        Ok(Pattern {
+            pattern: pattern.to_string(),
+            matcher: glob.compile_matcher(),
+            specificity,
+        })
+    }
+}
+
+/// Target path patterns: exact paths, globs such as `environments/prod/**`
+/// or `environments/{dev,test}`, and negations such as `!environments/prod/canary`.
+/// A path is targeted if it matches any pattern and no negation.
+pub struct Targets {
+    patterns: Vec<Pattern>,
+    negations: Vec<Pattern>,
+}
+
+impl Targets {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        let mut targets = Targets {
+            patterns: Vec::new(),
+            negations: Vec::new(),
+        };
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            match pattern.strip_prefix('!') {
+                Some(negation) => targets.negations.push(Pattern::new(negation)?),
+                None => targets.patterns.push(Pattern::new(pattern)?),
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Whitespace separated patterns, as used in keys.
+    pub fn parse(patterns: &str) -> Result<Self> {
+        Self::new(&patterns.split_whitespace().collect::<Vec<_>>())
+    }
+
+    /// The patterns that match none of the paths, with a suggestion for exact paths.
+    pub fn unknown<'a>(&'a self, paths: &'a BTreeSet<String>) -> Vec<(&'a str, Option<&'a str>)> {
+        self.patterns
+            .iter()
+            .chain(&self.negations)
+            .filter(|p| !paths.iter().any(|path| p.matcher.is_match(path)))
+            .map(|p| {
+                let suggestion = if p.specificity.exact {
+                    did_you_mean(&p.pattern, paths.iter().map(|path| path.as_str()))
+                } else {
+                    None
+                };
+                (p.pattern.as_str(), suggestion)
+            })
+            .collect()
+    }
+
+    /// The most specific pattern matching the path, or `None` if the path is not targeted.
+    pub fn matches(&self, path: &str) -> Option<Match<'_>> {
+        if self.negations.iter().any(|n| n.matcher.is_match(path)) {
+            return None;
+        }
+        self.patterns
+            .iter()
+            .filter(|p| p.matcher.is_match(path))
+            .max_by_key(|p| p.specificity)
+            .map(|p| Match {
+                specificity: p.specificity,
+                pattern: &p.pattern,
+            })
+    }
+}
+
+enum Segment {
+    Literal(String),
+    /// `{name}`
+    Capture(String),
+    /// `*`
+    Any,
+}
+
+/// A pattern like `{cloud}/{stage}/{region}` matching directories segment by segment
+/// and capturing the segments in braces.
+pub struct PathPattern {
+    pub pattern: String,
+    segments: Vec<Segment>,
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        for segment in pattern.split('/') {
+            segments.push(match segment {
+                "*" => Segment::Any,
+                _ if segment.starts_with('{') && segment.ends_with('}') => {
+                    let name = &segment[1..segment.len() - 1];
+                    if name.is_empty() || name.contains(['{', '}']) {
+                        bail!("invalid capture {segment} in path pattern {pattern}");
+                    }
+                    Segment::Capture(name.to_string())
+                }
+                _ if segment.contains(['{', '}', '*']) || segment.is_empty() => {
+                    bail!("invalid segment {segment} in path pattern {pattern}")
+                }
+                _ => Segment::Literal(segment.to_string()),
+            });
+        }
+        Ok(PathPattern {
+            pattern: pattern.to_string(),
+            segments,
+        })
+    }
+
+    /// The captured segments by name, or `None` if the path does not match.
+    pub fn captures(&self, path: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let parts = path.split('/').collect::<Vec<_>>();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+        let mut captures = serde_json::Map::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal != part => return None,
+                Segment::Capture(name) => {
+                    captures.insert(name.clone(), part.into());
+                }
+                _ => {}
+            }
+        }
+        Some(captures)
+    }
+}
+
+/// A pattern from a YAML value. An unquoted negation such as
+/// `- !environments/prod/canary` is parsed by YAML as a tag with no value.
+pub fn pattern_str(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(pattern) => Some(pattern.clone()),
+        serde_yaml::Value::Tagged(tagged) if tagged.value.is_null() => Some(tagged.tag.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_targets() -> Result<()> {
+        let targets = Targets::new(&["environments/prod/**", "!environments/prod/canary"])?;
+        assert!(targets.matches("environments/prod/westus").is_some());
+        assert!(targets.matches("environments/prod/westus/zone1").is_some());
+        assert!(targets.matches("environments/prod/canary").is_none());
+        assert!(targets.matches("environments/prod").is_none());
+        assert!(targets.matches("environments/dev/westus").is_none());
+
+        let targets = Targets::parse("environments/{dev,test}/*")?;
+        assert!(targets.matches("environments/dev/westus").is_some());
+        assert!(targets.matches("environments/test/eastus").is_some());
+        assert!(targets.matches("environments/test/eastus/zone1").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown() -> Result<()> {
+        let paths = [
+            "environments",
+            "environments/prod",
+            "environments/prod/westus",
+        ]
+        .map(String::from)
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+        let targets = Targets::new(&[
+            "environments/prod/westsu",
+            "environments/prod/*",
+            "environments/dev/*",
+            "!environments/prod/westus",
+        ])?;
+        assert_eq!(
+            targets.unknown(&paths),
+            vec![
+                ("environments/prod/westsu", Some("environments/prod/westus")),
+                ("environments/dev/*", None)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_pattern() -> Result<()> {
+        let pattern = PathPattern::parse("{cloud}/*/{region}")?;
+        assert_eq!(
+            pattern.captures("azure/prod/westus"),
+            serde_json::json!({"cloud": "azure", "region": "westus"})
+                .as_object()
+                .cloned()
+        );
+        assert!(pattern.captures("azure/prod").is_none());
+        let pattern = PathPattern::parse("azure/{stage}")?;
+        assert!(pattern.captures("aws/prod").is_none());
+        assert!(PathPattern::parse("{cloud}-{stage}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_specificity() -> Result<()> {
+        let path = "environments/prod/westus";
+        let specificity = |pattern| -> Result<_> {
+            let targets = Targets::new(&[pattern])?;
+            Ok(targets.matches(path).map(|m| m.specificity))
+        };
+        let exact = specificity(path)?;
+        let region = specificity("environments/prod/*")?;
+        let stage = specificity("environments/*/westus")?;
+        let all = specificity("environments/**")?;
+        assert!(exact > region);
+        assert!(region > stage);
+        assert!(stage > all);
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::anyhow;
+use anyhow::Result;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use serde_yaml::Value;
+use std::fmt;
+
+/// A value with an unexpected type or content in a source file.
+struct Problem {
+    path: Vec<String>,
+    message: String,
+}
+
+/// Collects the problems found while validating the shape of a source file,
+/// so that all of them can be reported at once.
+pub struct Problems<'s> {
+    file: Utf8PathBuf,
+    source: &'s str,
+    problems: Vec<Problem>,
+}
+
+impl<'s> Problems<'s> {
+    pub fn new(file: &Utf8Path, source: &'s str) -> Self {
+        Problems {
+            file: file.to_path_buf(),
+            source,
+            problems: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, path: &[String], expected: &'static str, found: &Value) {
+        self.push_message(
+            path,
+            format!("expected {expected}, found {}", type_name(found)),
+        );
+    }
+
+    pub fn push_message(&mut self, path: &[String], message: String) {
+        self.problems.push(Problem {
+            path: path.to_vec(),
+            message,
+        });
+    }
+
+    pub fn into_result(self) -> Result<()> {
+        if self.problems.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!("{self}"))
+    }
+}
+
+impl fmt::Display for Problems<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.problems.len();
+        let noun = if count == 1 { "problem" } else { "problems" };
+        write!(f, "{count} {noun} in {}:", self.file)?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", self.file)?;
+            if let Some(line) = find_line(self.source, &problem.path) {
+                write!(f, ":{line}")?;
+            }
+            write!(f, ": {}: {}", key_path(&problem.path), problem.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// A path segment for the index of a sequence item.
+pub fn index(i: usize) -> String {
+    format!("[{i}]")
+}
+
+/// A path segment for a mapping key.
+pub fn key(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.clone(),
+        Value::Number(key) => key.to_string(),
+        Value::Bool(key) => key.to_string(),
+        Value::Null => "null".to_string(),
+        _ => serde_yaml::to_string(key)
+            .map(|key| key.trim_end().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+fn key_path(path: &[String]) -> String {
+    let mut key_path = String::new();
+    for segment in path {
+        if !key_path.is_empty() && !segment.starts_with('[') {
+            key_path.push('.');
+        }
+        key_path.push_str(segment);
+    }
+    if key_path.is_empty() {
+        key_path.push_str("(root)");
+    }
+    key_path
+}
+
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Sequence(_) => "sequence",
+        Value::Mapping(_) => "mapping",
+        Value::Tagged(_) => "tagged value",
+    }
+}
+
+/// The candidate closest to a name, if it is close enough to be a likely typo.
+pub fn did_you_mean<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.len() / 4).clamp(1, 4);
+    candidates
+        .map(|candidate| (strsim::levenshtein(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Finds the 1-based line of a key path in block style YAML source.
+/// Returns the line of the deepest segment that could be found.
+fn find_line(source: &str, path: &[String]) -> Option<usize> {
+    let lines = source.lines().collect::<Vec<_>>();
+    let mut found = None;
+    let mut start = 0;
+    let mut parent_indent: Option<usize> = None;
+    // the first key of a sequence item is on the same line as its dash
+    let mut item_line = false;
+    for segment in path {
+        let index = segment
+            .strip_prefix('[')
+            .and_then(|n| n.strip_suffix(']'))
+            .and_then(|n| n.parse::<usize>().ok());
+        let mut item = 0;
+        let mut next = None;
+        for (i, line) in lines.iter().enumerate().skip(start) {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let line_indent = indent(line);
+            let is_item = trimmed.starts_with("- ") || trimmed == "-";
+            let first = item_line && i == start;
+            if let Some(parent) = parent_indent {
+                // sequence items may be at the same indentation as their key
+                let child = line_indent > parent || (is_item && index.is_some());
+                if !first && (line_indent < parent || !child) {
+                    break;
+                }
+            }
+            if let Some(n) = index {
+                if is_item && !first {
+                    if item == n {
+                        next = Some((i, line_indent, true));
+                        break;
+                    }
+                    item += 1;
+                }
+            } else {
+                let content = if first {
+                    trimmed.trim_start_matches('-').trim_start()
+                } else {
+                    trimmed
+                };
+                let matches = [
+                    format!("{segment}:"),
+                    format!("\"{segment}\":"),
+                    format!("'{segment}':"),
+                ]
+                .iter()
+                .any(|k| content.starts_with(k.as_str()));
+                if matches {
+                    next = Some((i, line_indent, false));
+                    break;
+                }
+            }
+        }
+        let (i, line_indent, is_index) = next?;
+        found = Some(i + 1);
+        start = if is_index { i } else { i + 1 };
+        item_line = is_index;
+        parent_indent = Some(line_indent);
+        // flow style values are on the same line
+        let line = lines[i].trim_end();
+        if line.ends_with(']') || line.ends_with('}') {
+            break;
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_line() {
+        let source = r#"
+# flags
+featureX:
+  true:
+    - environments/prod
+    - 7
+  false: environments/dev
+replicas:
+  3: [environments/prod, 4]
+includes:
+- path: shared
+  optional: true
+- name: other
+"#;
+        let path = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(find_line(source, &path(&["featureX"])), Some(3));
+        assert_eq!(
+            find_line(source, &path(&["featureX", "true", "[1]"])),
+            Some(6)
+        );
+        assert_eq!(find_line(source, &path(&["featureX", "false"])), Some(7));
+        assert_eq!(find_line(source, &path(&["replicas", "3", "[1]"])), Some(9));
+        assert_eq!(
+            find_line(source, &path(&["includes", "[0]", "optional"])),
+            Some(12)
+        );
+        assert_eq!(
+            find_line(source, &path(&["includes", "[1]", "name"])),
+            Some(13)
+        );
+        assert_eq!(find_line(source, &path(&["missing"])), None);
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        let candidates = ["environments/prod/westus", "environments/prod/eastus"];
+        assert_eq!(
+            did_you_mean("environments/prod/westsu", candidates.into_iter()),
+            Some("environments/prod/westus")
+        );
+        assert_eq!(did_you_mean("shared", candidates.into_iter()), None);
+    }
+
+    #[test]
+    fn test_key_path() {
+        let path = ["featureX", "true", "[2]"].map(String::from);
+        assert_eq!(key_path(&path), "featureX.true[2]");
+        assert_eq!(key_path(&[]), "(root)");
+    }
+}
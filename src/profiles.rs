@@ -0,0 +1,153 @@
+use anyhow::bail;
+use anyhow::Result;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use std::collections::BTreeMap;
+
+use crate::list_yml_paths;
+
+/// The profile of the untracked `*.local.yml` files and `local/` tree of developers,
+/// merged last at each environment directory.
+pub const LOCAL: &str = "local";
+
+/// The profile names: those of the manifest, then the directories of the overlays directory.
+pub fn names(manifest_profiles: &[String], overlays_path: &Utf8Path) -> Result<Vec<String>> {
+    if manifest_profiles.iter().any(|name| name == LOCAL) {
+        bail!("profile name {LOCAL} is reserved for local override files");
+    }
+    let mut names = manifest_profiles.to_vec();
+    if let Ok(entries) = overlays_path.read_dir_utf8() {
+        let mut dirs = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if entry.file_name() == LOCAL {
+                    bail!("profile name {LOCAL} is reserved for local override files");
+                }
+                dirs.push(entry.file_name().to_string());
+            }
+        }
+        dirs.sort();
+        for dir in dirs {
+            if !names.contains(&dir) {
+                names.push(dir);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// The profile of an environment file like `region.canary.yml`, if it is a profile name.
+pub fn profile_of<'a>(file: &Utf8Path, names: &'a [String]) -> Option<&'a str> {
+    let profile = Utf8Path::new(file.file_stem()?).extension()?;
+    names
+        .iter()
+        .find(|name| *name == profile)
+        .map(|name| name.as_str())
+}
+
+/// The files of the active profiles by environment directory, in the order of the profiles:
+/// for each profile its `*.<profile>.yml` environment files, then its overlay tree.
+pub fn files(
+    active: &[String],
+    names: &[String],
+    environments_path: &Utf8Path,
+    profile_ymls: &[&Utf8Path],
+    overlays_path: &Utf8Path,
+) -> Result<BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>>> {
+    let mut files: BTreeMap<Utf8PathBuf, Vec<Utf8PathBuf>> = BTreeMap::new();
+    for profile in active {
+        if !names.contains(profile) {
+            bail!("unknown profile {profile}, expected one of {names:?}");
+        }
+        let overlay_path = overlays_path.join(profile);
+        let siblings = profile_ymls
+            .iter()
+            .filter(|yml| profile_of(yml, names) == Some(profile))
+            .map(|yml| (environments_path, environments_path.join(yml)));
+        let overlays = list_yml_paths(&overlay_path)
+            .into_iter()
+            .map(|yml_path| (overlay_path.as_path(), yml_path));
+        for (root, yml_path) in siblings.chain(overlays) {
+            let relative = yml_path.strip_prefix(root)?;
+            let dir = relative.parent().unwrap_or(Utf8Path::new(""));
+            files.entry(dir.to_path_buf()).or_default().push(yml_path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::yaml;
+
+    #[test]
+    fn test_profiles() -> Result<()> {
+        let ev2_path = yaml::test::temp_ev2(
+            "profiles",
+            &[
+                ("environments/prod/westus/region.yml", "{}"),
+                ("environments/prod/westus/region.canary.yml", "{}"),
+                ("environments/prod/region.perf.yml", "{}"),
+                ("overlays/canary/prod/westus/extra.yml", "{}"),
+                ("overlays/local-dev/prod/local.yml", "{}"),
+            ],
+        )?;
+        let overlays_path = ev2_path.join("overlays");
+        let names = names(&["perf".to_string()], &overlays_path)?;
+        assert_eq!(names, ["perf", "canary", "local-dev"]);
+        assert_eq!(
+            profile_of(Utf8Path::new("prod/region.canary.yml"), &names),
+            Some("canary")
+        );
+        assert_eq!(profile_of(Utf8Path::new("prod/region.yml"), &names), None);
+        assert_eq!(profile_of(Utf8Path::new("prod/a.b.yml"), &names), None);
+        assert_eq!(
+            profile_of(Utf8Path::new("prod/region.local.yml"), &[LOCAL.to_string()]),
+            Some(LOCAL)
+        );
+
+        let environments_path = ev2_path.join("environments");
+        let profile_ymls = [
+            Utf8Path::new("prod/westus/region.canary.yml"),
+            Utf8Path::new("prod/region.perf.yml"),
+        ];
+        let active = ["canary".to_string(), "perf".to_string()];
+        let files = files(
+            &active,
+            &names,
+            &environments_path,
+            &profile_ymls,
+            &overlays_path,
+        )?;
+        let relative = |dir: &str| -> Vec<String> {
+            files[Utf8Path::new(dir)]
+                .iter()
+                .map(|yml| yml.strip_prefix(&ev2_path).unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(relative("prod"), ["environments/prod/region.perf.yml"]);
+        assert_eq!(
+            relative("prod/westus"),
+            [
+                "environments/prod/westus/region.canary.yml",
+                "overlays/canary/prod/westus/extra.yml"
+            ]
+        );
+        assert!(super::names(&["local".to_string()], &overlays_path).is_err());
+        let err = super::files(
+            &["qa".to_string()],
+            &names,
+            &environments_path,
+            &[],
+            &overlays_path,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"unknown profile qa, expected one of ["perf", "canary", "local-dev"]"#
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,523 @@
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use clap::Args;
+use std::fs;
+
+use crate::flags::load_flags;
+use crate::flags::parse_flags;
+use crate::flags::Flags;
+use crate::manifest::VERSIONS;
+use crate::patterns::Targets;
+use crate::report;
+use crate::versions::check_version;
+use crate::versions::parse_version;
+use crate::Project;
+
+#[derive(Args)]
+pub struct PromoteArgs {
+    /// The component name in versions.yml
+    component: String,
+    /// The version to promote to
+    version: String,
+    /// The target path pattern to promote, such as `environments/prod/**`
+    #[arg(long)]
+    to: String,
+    /// Show the changes without writing versions.yml
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Promotes a component version to the paths matching a pattern by
+/// moving them between version buckets of `versions.yml`.
+pub fn promote(project: &Project, args: &PromoteArgs) -> Result<()> {
+    parse_version(&args.version)
+        .with_context(|| format!("invalid semantic version {}", args.version))?;
+    let to = Targets::new(&[&args.to])?;
+    let ancestor_paths = project.ancestor_paths();
+    if let Some((pattern, suggestion)) = to.unknown(&ancestor_paths).first() {
+        let mut message = format!("--to {pattern} matches no environment directory");
+        if let Some(suggestion) = suggestion {
+            message.push_str(&format!(", did you mean {suggestion}?"));
+        }
+        bail!(message);
+    }
+    let current = &project.matrix(VERSIONS)?.flags;
+    let versions_yml = versions_yml(current, &args.component)?;
+    let source = fs::read_to_string(&versions_yml)
+        .with_context(|| format!("reading file {versions_yml}"))?;
+    let promoted = promote_source(&source, &args.component, &args.version, &args.to)?;
+    let mut versions = Flags::default();
+    for yml in current.ymls() {
+        versions.extend(if *yml == versions_yml {
+            parse_flags(yml, &promoted, check_version)
+                .with_context(|| format!("checking promoted {versions_yml}"))?
+        } else {
+            load_flags(yml)?
+        });
+    }
+
+    let mut changed = false;
+    for dir in project.dirs() {
+        let before = component_version(project, current, dir, &args.component)?;
+        let after = component_version(project, &versions, dir, &args.component)?;
+        if before != after {
+            changed = true;
+            println!(
+                "{}: {} -> {}",
+                project.ancestor_path(dir),
+                before.as_deref().unwrap_or("none"),
+                after.as_deref().unwrap_or("none")
+            );
+        }
+    }
+    if !changed {
+        println!("no environment versions change");
+    } else if !args.dry_run {
+        fs::write(&versions_yml, promoted).with_context(|| format!("writing {versions_yml}"))?;
+    }
+    Ok(())
+}
+
+/// The versions file with the component, or the first one if none has it.
+fn versions_yml(versions: &Flags, component: &str) -> Result<Utf8PathBuf> {
+    let ymls = versions.ymls_of(component);
+    if ymls.len() > 1 {
+        let ymls = ymls.iter().map(|yml| yml.as_str()).collect::<Vec<_>>();
+        bail!(
+            "{component} is in several versions files: {}",
+            ymls.join(", ")
+        );
+    }
+    ymls.first()
+        .map(|yml| yml.to_path_buf())
+        .or_else(|| versions.ymls().first().cloned())
+        .ok_or_else(|| anyhow!("no versions file to promote {component} in"))
+}
+
+fn component_version(
+    project: &Project,
+    versions: &Flags,
+    dir: &camino::Utf8Path,
+    component: &str,
+) -> Result<Option<String>> {
+    let values = report::resolve(project, versions, dir)?;
+    Ok(values.get(component).map(|v| match v {
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }))
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+fn is_content(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    !trimmed.is_empty() && !trimmed.starts_with('#')
+}
+
+/// Removes a trailing comment and quotes from a scalar.
+fn scalar(text: &str) -> &str {
+    let text = match text.find(" #") {
+        Some(i) => &text[..i],
+        None => text,
+    }
+    .trim();
+    text.trim_matches('"').trim_matches('\'')
+}
+
+/// The trailing comment of a line with its leading spaces, or an empty string.
+fn comment(line: &str) -> &str {
+    match line.find(" #") {
+        Some(i) => &line[i..],
+        None => "",
+    }
+}
+
+/// The key of a `key: value` line and the text after the colon.
+fn key_value(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    let (key, value) = if let Some(rest) = trimmed.strip_prefix('"') {
+        let end = rest.find('"')?;
+        (&rest[..end], rest[end + 1..].strip_prefix(':')?)
+    } else if let Some(rest) = trimmed.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+        (&rest[..end], rest[end + 1..].strip_prefix(':')?)
+    } else {
+        let end = trimmed.find(": ").or_else(|| {
+            trimmed
+                .strip_suffix(':')
+                .map(|key| key.len())
+                .or_else(|| scalar(trimmed).strip_suffix(':').map(|key| key.len()))
+        })?;
+        (&trimmed[..end], &trimmed[end + 1..])
+    };
+    Some((key, value))
+}
+
+/// A version and the paths it applies to.
+struct Bucket {
+    version: String,
+    key_line: usize,
+    /// the line after the last line of the bucket
+    end: usize,
+    /// the paths are in a flow sequence on the key line
+    flow: bool,
+    /// line and path of each block sequence item
+    items: Vec<(usize, String)>,
+}
+
+impl Bucket {
+    fn paths(&self, lines: &[String]) -> Vec<String> {
+        if self.flow {
+            flow_items(&lines[self.key_line])
+        } else {
+            self.items.iter().map(|(_, path)| path.clone()).collect()
+        }
+    }
+
+    /// The trailing comment of the item of a path, kept when the path moves.
+    fn comment<'l>(&self, lines: &'l [String], path: &str) -> &'l str {
+        self.items
+            .iter()
+            .find(|(_, item)| item == path)
+            .map_or("", |(line, _)| comment(&lines[*line]))
+    }
+}
+
+fn flow_items(line: &str) -> Vec<String> {
+    let (Some(start), Some(end)) = (line.find('['), line.rfind(']')) else {
+        return Vec::new();
+    };
+    line[start + 1..end]
+        .split(',')
+        .map(scalar)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect()
+}
+
+/// Rewrites the source of `versions.yml` so that the paths of a component that
+/// match a pattern use the given version, keeping comments and formatting.
+/// If no listed path matches the pattern, the pattern itself is added.
+pub fn promote_source(source: &str, component: &str, version: &str, to: &str) -> Result<String> {
+    let targets = Targets::new(&[to])?;
+    let mut lines = source.lines().map(|l| l.to_string()).collect::<Vec<_>>();
+
+    // find the component
+    let component_line = lines.iter().position(|line| {
+        indent(line) == 0 && key_value(line).map(|(key, _)| key) == Some(component)
+    });
+    let Some(component_line) = component_line else {
+        lines.push(format!("{component}:"));
+        lines.push(format!("  {version}:"));
+        lines.push(format!("    - {to}"));
+        return Ok(join(lines, source));
+    };
+    if !key_value(&lines[component_line])
+        .map(|(_, value)| scalar(value).is_empty())
+        .unwrap_or(false)
+    {
+        bail!("{component} must be a block mapping of versions to paths");
+    }
+    let component_end = (component_line + 1..lines.len())
+        .find(|&i| is_content(&lines[i]) && indent(&lines[i]) == 0)
+        .unwrap_or(lines.len());
+
+    // find the buckets
+    let bucket_indent = (component_line + 1..component_end)
+        .find(|&i| is_content(&lines[i]))
+        .map(|i| indent(&lines[i]))
+        .unwrap_or(2);
+    let mut buckets: Vec<Bucket> = Vec::new();
+    let mut item_indent = None;
+    for (i, line) in lines
+        .iter()
+        .enumerate()
+        .take(component_end)
+        .skip(component_line + 1)
+    {
+        if !is_content(line) {
+            continue;
+        }
+        if indent(line) == bucket_indent && !line.trim_start().starts_with('-') {
+            let (key, value) =
+                key_value(line).ok_or_else(|| anyhow!("expected a version at line {}", i + 1))?;
+            if let Some(bucket) = buckets.last_mut() {
+                bucket.end = i;
+            }
+            buckets.push(Bucket {
+                version: key.to_string(),
+                key_line: i,
+                end: component_end,
+                flow: value.trim_start().starts_with('['),
+                items: Vec::new(),
+            });
+        } else if let Some(item) = line.trim_start().strip_prefix("- ") {
+            let bucket = buckets
+                .last_mut()
+                .ok_or_else(|| anyhow!("expected a version at line {}", i + 1))?;
+            item_indent.get_or_insert(indent(line));
+            bucket.items.push((i, scalar(item).to_string()));
+        }
+    }
+    // trailing blank lines and comments belong to what follows
+    for bucket in &mut buckets {
+        while bucket.end > bucket.key_line + 1 && !is_content(&lines[bucket.end - 1]) {
+            bucket.end -= 1;
+        }
+    }
+    let item_indent = " ".repeat(item_indent.unwrap_or(bucket_indent + 2));
+
+    // move the matching paths out of the other buckets
+    let mut moved = Vec::new();
+    let mut remove_lines = Vec::new();
+    let mut edits = Vec::new();
+    for bucket in buckets.iter().filter(|b| b.version != version) {
+        let paths = bucket.paths(&lines);
+        let (matching, keep): (Vec<_>, Vec<_>) = paths
+            .into_iter()
+            .partition(|path| !path.starts_with('!') && targets.matches(path).is_some());
+        if matching.is_empty() {
+            continue;
+        }
+        moved.extend(matching.into_iter().map(|path| {
+            let comment = bucket.comment(&lines, &path).to_string();
+            (path, comment)
+        }));
+        if keep.is_empty() {
+            remove_lines.extend(bucket.key_line..bucket.end);
+        } else if bucket.flow {
+            edits.push((bucket.key_line, flow_line(&lines[bucket.key_line], &keep)));
+        } else {
+            for (line, path) in &bucket.items {
+                if !keep.contains(path) {
+                    remove_lines.push(*line);
+                }
+            }
+        }
+    }
+    if moved.is_empty() {
+        moved.push((to.to_string(), String::new()));
+    }
+
+    // add the paths to the target bucket
+    let mut inserts = Vec::new();
+    match buckets.iter().find(|b| b.version == version) {
+        Some(bucket) => {
+            let mut paths = bucket.paths(&lines);
+            moved.retain(|(path, _)| !paths.contains(path));
+            if bucket.flow {
+                paths.extend(moved.iter().map(|(path, _)| path.clone()));
+                let mut line = flow_line(&lines[bucket.key_line], &paths);
+                // a flow sequence has one comment for all its items
+                for (_, comment) in &moved {
+                    if !line.contains(comment.trim_start()) {
+                        line.push_str(comment);
+                    }
+                }
+                edits.push((bucket.key_line, line));
+            } else {
+                let after = bucket
+                    .items
+                    .last()
+                    .map_or(bucket.key_line, |(line, _)| *line);
+                let new_lines = moved
+                    .iter()
+                    .map(|(path, comment)| format!("{item_indent}- {path}{comment}"));
+                inserts.push((after + 1, new_lines.collect::<Vec<_>>()));
+            }
+        }
+        None => {
+            let after = buckets.last().map_or(component_line + 1, |b| b.end);
+            let mut new_lines = vec![format!("{}{version}:", " ".repeat(bucket_indent))];
+            new_lines.extend(
+                moved
+                    .iter()
+                    .map(|(path, comment)| format!("{item_indent}- {path}{comment}")),
+            );
+            inserts.push((after, new_lines));
+        }
+    }
+
+    for (line, text) in edits {
+        lines[line] = text;
+    }
+    // apply from the bottom up so that line numbers stay valid
+    let mut changes: Vec<(usize, Option<Vec<String>>)> = remove_lines
+        .into_iter()
+        .map(|line| (line, None))
+        .chain(inserts.into_iter().map(|(line, new)| (line, Some(new))))
+        .collect();
+    changes.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.is_some().cmp(&b.1.is_some())));
+    for (line, change) in changes {
+        match change {
+            None => {
+                lines.remove(line);
+            }
+            Some(new_lines) => {
+                lines.splice(line..line, new_lines);
+            }
+        }
+    }
+    Ok(join(lines, source))
+}
+
+fn flow_line(line: &str, paths: &[String]) -> String {
+    let start = line.find('[').unwrap_or(line.len());
+    let end = line.rfind(']').map_or(line.len(), |end| end + 1);
+    format!("{}[{}]{}", &line[..start], paths.join(", "), &line[end..])
+}
+
+fn join(lines: Vec<String>, source: &str) -> String {
+    let mut joined = lines.join("\n");
+    if source.ends_with('\n') {
+        joined.push('\n');
+    }
+    joined
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use clap::Parser;
+
+    const SOURCE: &str = r#"# component versions
+api:
+  # current
+  1.1.0:
+    - environments/prod/eastus # pinned
+    - environments/prod/westus
+    - environments/test
+  1.2.0:
+    - environments/dev
+
+web:
+  2.0.0: [environments/prod/westus, environments/dev]
+"#;
+
+    #[test]
+    fn test_promote_block() -> Result<()> {
+        assert_eq!(
+            promote_source(SOURCE, "api", "1.2.0", "environments/prod/*")?,
+            r#"# component versions
+api:
+  # current
+  1.1.0:
+    - environments/test
+  1.2.0:
+    - environments/dev
+    - environments/prod/eastus # pinned
+    - environments/prod/westus
+
+web:
+  2.0.0: [environments/prod/westus, environments/dev]
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_new_bucket() -> Result<()> {
+        assert_eq!(
+            promote_source(SOURCE, "api", "1.3.0", "environments/{dev,test}")?,
+            r#"# component versions
+api:
+  # current
+  1.1.0:
+    - environments/prod/eastus # pinned
+    - environments/prod/westus
+  1.3.0:
+    - environments/test
+    - environments/dev
+
+web:
+  2.0.0: [environments/prod/westus, environments/dev]
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_flow() -> Result<()> {
+        let promoted = promote_source(SOURCE, "web", "2.1.0", "environments/dev")?;
+        assert!(promoted.ends_with(
+            r#"web:
+  2.0.0: [environments/prod/westus]
+  2.1.0:
+    - environments/dev
+"#
+        ));
+        let promoted = promote_source(&promoted, "web", "2.1.0", "environments/prod/**")?;
+        assert!(promoted.ends_with(
+            r#"web:
+  2.1.0:
+    - environments/dev
+    - environments/prod/westus
+"#
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_to_flow() -> Result<()> {
+        let source =
+            "api:\n  1.0.0:\n    - environments/prod # pinned\n  2.0.0: [environments/dev]\n";
+        assert_eq!(
+            promote_source(source, "api", "2.0.0", "environments/prod")?,
+            "api:\n  2.0.0: [environments/dev, environments/prod] # pinned\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_unknown_target() -> Result<()> {
+        let versions = "api:\n  1.2.0: [environments/prod/westus]\n";
+        let ev2_path = crate::yaml::test::temp_ev2(
+            "promote_unknown_target",
+            &[
+                ("versions.yml", versions),
+                ("environments/prod/westus/region.yml", "region: westus"),
+            ],
+        )?;
+        let cli = crate::Cli::parse_from(["configur", "--ev2", ev2_path.as_str()]);
+        let project = Project::load(&cli)?;
+        let promote_to = |to: &str| {
+            let args = PromoteArgs {
+                component: "api".to_string(),
+                version: "1.3.0".to_string(),
+                to: to.to_string(),
+                dry_run: false,
+            };
+            promote(&project, &args)
+        };
+        let err = promote_to("environments/prd/*").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--to environments/prd/* matches no environment directory"
+        );
+        let err = promote_to("environments/prod/westsu").unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("did you mean environments/prod/westus?"));
+        assert_eq!(fs::read_to_string(ev2_path.join("versions.yml"))?, versions);
+
+        promote_to("environments/prod/*")?;
+        assert_eq!(
+            fs::read_to_string(ev2_path.join("versions.yml"))?,
+            "api:\n  1.3.0:\n    - environments/prod/westus\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_promote_pattern() -> Result<()> {
+        // no listed path matches, so the pattern is added
+        let promoted = promote_source(SOURCE, "db", "1.0.0", "environments/**")?;
+        assert!(promoted.ends_with("db:\n  1.0.0:\n    - environments/**\n"));
+        Ok(())
+    }
+}
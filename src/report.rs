@@ -0,0 +1,195 @@
+use anyhow::Context;
+use anyhow::Result;
+use camino::Utf8Path;
+use clap::Args;
+use clap::ValueEnum;
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use serde_json_merge::Dfs;
+use serde_json_merge::Merge;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use crate::flags::Flags;
+use crate::patterns::Targets;
+use crate::Project;
+
+#[derive(Args)]
+pub struct MatrixArgs {
+    /// The matrix to show: flags, versions or a matrix of the manifest
+    #[arg(default_value = "flags")]
+    matrix: String,
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Markdown)]
+    format: Format,
+    /// Only show names matching these globs
+    #[arg(short, long)]
+    name: Vec<String>,
+    /// Only show environment directories matching these target path patterns
+    #[arg(short, long)]
+    dir: Vec<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Markdown,
+    Csv,
+    Json,
+}
+
+/// Values by name for each environment directory, by target path.
+#[derive(Default)]
+struct Matrix {
+    names: BTreeSet<String>,
+    rows: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Prints the flag or version values of each environment directory.
+pub fn matrix(project: &Project, args: &MatrixArgs) -> Result<()> {
+    let flags = &project.matrix(&args.matrix)?.flags;
+    let names = glob_set(&args.name)?;
+    let dirs = Targets::new(&args.dir)?;
+
+    let mut matrix = Matrix::default();
+    for dir in project.dirs() {
+        let dir_path = project.ancestor_path(dir);
+        if !args.dir.is_empty() && dirs.matches(&dir_path).is_none() {
+            continue;
+        }
+        let mut values = resolve(project, flags, dir)?;
+        if !args.name.is_empty() {
+            values.retain(|name, _| names.is_match(name));
+        }
+        matrix.names.extend(values.keys().cloned());
+        matrix.rows.insert(dir_path, values);
+    }
+
+    let output = match args.format {
+        Format::Markdown => matrix.markdown(),
+        Format::Csv => matrix.csv(),
+        Format::Json => serde_json::to_string_pretty(&matrix.rows)?,
+    };
+    println!("{output}");
+    Ok(())
+}
+
+/// The values of an environment directory, merged from its ancestors down.
+pub fn resolve(
+    project: &Project,
+    flags: &Flags,
+    dir: &Utf8Path,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut values = serde_json::Value::Object(serde_json::Map::new());
+    for (_, ancestor_path) in project.stack(dir) {
+        if let Some(json) = flags.get(&ancestor_path)? {
+            values = values.merged_recursive::<Dfs>(&json);
+        }
+    }
+    match values {
+        serde_json::Value::Object(values) => Ok(values),
+        _ => Ok(serde_json::Map::new()),
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("invalid glob {pattern}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+fn cell(value: Option<&serde_json::Value>) -> String {
+    match value {
+        None => String::new(),
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+impl Matrix {
+    fn table(&self) -> Vec<Vec<String>> {
+        let mut header = vec!["directory".to_string()];
+        header.extend(self.names.iter().cloned());
+        let mut table = vec![header];
+        for (dir, values) in &self.rows {
+            let mut row = vec![dir.clone()];
+            row.extend(self.names.iter().map(|name| cell(values.get(name))));
+            table.push(row);
+        }
+        table
+    }
+
+    fn markdown(&self) -> String {
+        let table = self.table();
+        let mut lines = Vec::new();
+        for (i, row) in table.iter().enumerate() {
+            let cells = row
+                .iter()
+                .map(|c| c.replace('|', "\\|"))
+                .collect::<Vec<_>>();
+            lines.push(format!("| {} |", cells.join(" | ")));
+            if i == 0 {
+                lines.push(format!("|{}", " --- |".repeat(row.len())));
+            }
+        }
+        lines.join("\n")
+    }
+
+    fn csv(&self) -> String {
+        let quote = |c: &String| {
+            if c.contains([',', '"', '\n']) {
+                format!("\"{}\"", c.replace('"', "\"\""))
+            } else {
+                c.clone()
+            }
+        };
+        self.table()
+            .iter()
+            .map(|row| row.iter().map(quote).collect::<Vec<_>>().join(","))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn matrix() -> Matrix {
+        let mut matrix = Matrix::default();
+        matrix.names.extend(["featureX".into(), "zones".into()]);
+        let row = |value: serde_json::Value| value.as_object().unwrap().clone();
+        matrix.rows.insert(
+            "environments/prod".into(),
+            row(json!({"featureX": true, "zones": [1, 2]})),
+        );
+        matrix
+            .rows
+            .insert("environments/dev".into(), row(json!({"featureX": "a|b"})));
+        matrix
+    }
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            matrix().markdown(),
+            "| directory | featureX | zones |
+| --- | --- | --- |
+| environments/dev | a\\|b |  |
+| environments/prod | true | [1,2] |"
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            matrix().csv(),
+            "directory,featureX,zones
+environments/dev,a|b,
+environments/prod,true,\"[1,2]\""
+        );
+    }
+}
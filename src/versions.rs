@@ -0,0 +1,174 @@
+use anyhow::Context;
+use anyhow::Result;
+use camino::Utf8Path;
+use semver::Version;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+
+use crate::flags;
+use crate::flags::Flags;
+use crate::manifest::VERSIONS;
+use crate::problems::type_name;
+use crate::Project;
+
+/// Parses a semantic version, allowing a leading `v`.
+pub fn parse_version(version: &str) -> Result<Version, semver::Error> {
+    Version::parse(version.strip_prefix('v').unwrap_or(version))
+}
+
+/// Loads `versions.yml`, which has the same shape as `flags.yml`,
+/// checking that each version is a semantic version.
+pub fn load_versions(yml: &Utf8Path) -> Result<Flags> {
+    let source = fs::read_to_string(yml).with_context(|| format!("reading file {yml}"))?;
+    flags::parse_flags(yml, &source, check_version)
+}
+
+pub fn check_version(value: &serde_yaml::Value) -> Result<(), String> {
+    match value {
+        serde_yaml::Value::String(version) => parse_version(version)
+            .map(|_| ())
+            .map_err(|err| format!("invalid semantic version {version}: {err}")),
+        _ => Err(format!(
+            "expected semantic version string, found {}",
+            type_name(value)
+        )),
+    }
+}
+
+/// A directory that pins an older version than its parent.
+pub struct Downgrade {
+    pub path: String,
+    pub name: String,
+    pub version: String,
+    pub parent_path: String,
+    pub parent_version: String,
+}
+
+impl fmt::Display for Downgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pins {} {}, older than {} from {}",
+            self.path, self.name, self.version, self.parent_version, self.parent_path
+        )
+    }
+}
+
+/// Finds directories that pin an older version than the one they inherit.
+pub fn downgrades(project: &Project) -> Result<Vec<Downgrade>> {
+    let mut downgrades = Vec::new();
+    let mut seen = BTreeSet::new();
+    let versions = &project.matrix(VERSIONS)?.flags;
+    for dir in project.dirs() {
+        // version and the path it was set at, by name
+        let mut inherited: BTreeMap<String, (String, String)> = BTreeMap::new();
+        for (_, path) in project.stack(dir) {
+            let Some(serde_json::Value::Object(versions)) = versions.get(&path)? else {
+                continue;
+            };
+            for (name, version) in versions {
+                let Some(version) = version.as_str() else {
+                    continue;
+                };
+                if let Some((parent_version, parent_path)) = inherited.get(&name) {
+                    if is_older(version, parent_version)
+                        && seen.insert((path.clone(), name.clone()))
+                    {
+                        downgrades.push(Downgrade {
+                            path: path.clone(),
+                            name: name.clone(),
+                            version: version.to_string(),
+                            parent_path: parent_path.clone(),
+                            parent_version: parent_version.clone(),
+                        });
+                    }
+                }
+                inherited.insert(name, (version.to_string(), path.clone()));
+            }
+        }
+    }
+    Ok(downgrades)
+}
+
+fn is_older(version: &str, than: &str) -> bool {
+    match (parse_version(version), parse_version(than)) {
+        (Ok(version), Ok(than)) => version < than,
+        _ => false,
+    }
+}
+
+/// Prints the directories that pin an older version than their parent.
+pub fn report_downgrades(project: &Project) -> Result<()> {
+    for downgrade in downgrades(project)? {
+        println!("{downgrade}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_check_version() {
+        let source = r#"
+api:
+  1.2.0: [environments]
+  v1.3.0-beta.1: [environments/dev]
+  1.2: [environments/prod]
+  latest: [environments/test]
+"#;
+        let err = flags::parse_flags(Utf8Path::new("versions.yml"), source, check_version)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "2 problems in versions.yml:
+  versions.yml:5: api.1.2: expected semantic version string, found number
+  versions.yml:6: api.latest: invalid semantic version latest: unexpected character 'l' while parsing major version number"
+        );
+    }
+
+    #[test]
+    fn test_downgrades() -> Result<()> {
+        let ev2_path = crate::yaml::test::temp_ev2(
+            "downgrades",
+            &[
+                ("flags.yml", "{}"),
+                ("include.yml", "{}"),
+                (
+                    "versions.yml",
+                    "api:\n  1.2.0: [environments/prod]\n  1.1.0: [environments/prod/westus]\n  1.3.0: [environments/prod/eastus]\n  1.0.0: [environments/dr]\n",
+                ),
+                ("environments/prod/westus/region.yml", "region: westus"),
+                ("environments/prod/eastus/region.yml", "region: eastus"),
+                ("environments/dr/region.yml", "extends: prod/eastus"),
+            ],
+        )?;
+        let cli = crate::Cli::parse_from(["configur", "--ev2", ev2_path.as_str()]);
+        let project = Project::load(&cli)?;
+        let downgrades = downgrades(&project)?
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            downgrades,
+            vec![
+                "environments/dr pins api 1.0.0, older than 1.3.0 from environments/prod/eastus",
+                "environments/prod/westus pins api 1.1.0, older than 1.2.0 from environments/prod",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_older() {
+        assert!(is_older("1.1.0", "1.2.0"));
+        assert!(is_older("v1.2.0-beta.1", "1.2.0"));
+        assert!(!is_older("1.10.0", "1.9.0"));
+        assert!(!is_older("latest", "1.9.0"));
+    }
+}
@@ -0,0 +1,391 @@
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use camino::Utf8Component;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Named YAML fragments shared across source files.
+/// A fragment is a top-level key of a file in the anchors directory and is named
+/// by the file path relative to that directory, without the extension, and the key.
+/// `6140` in `anchors/cpu.yml` is referenced as `!ref cpu/6140`.
+#[derive(Default)]
+pub struct Anchors {
+    fragments: BTreeMap<String, Value>,
+}
+
+impl Anchors {
+    pub fn load(anchors_path: &Utf8Path) -> Result<Self> {
+        let mut fragments = BTreeMap::new();
+        if !anchors_path.exists() {
+            return Ok(Anchors { fragments });
+        }
+        for yml_path in crate::list_yml_paths(anchors_path) {
+            // glob drops a leading `./`
+            let name = yml_path
+                .strip_prefix(anchors_path)
+                .or_else(|_| yml_path.strip_prefix(anchors_path.as_str().trim_start_matches("./")))
+                .with_context(|| format!("strip prefix {anchors_path}"))?
+                .with_extension("")
+                .to_string()
+                .replace('\\', "/");
+            let value: Value = serde_yaml::from_slice(
+                &fs::read(&yml_path).with_context(|| format!("reading file {yml_path}"))?,
+            )
+            .with_context(|| format!("reading yml {yml_path}"))?;
+            let Value::Mapping(mapping) = value else {
+                bail!("anchors file {yml_path} must be a mapping of fragment names");
+            };
+            for (key, fragment) in mapping {
+                let key = scalar_key(&key)
+                    .with_context(|| format!("fragment name in anchors file {yml_path}"))?;
+                fragments.insert(format!("{name}/{key}"), fragment);
+            }
+        }
+        Ok(Anchors { fragments })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.fragments.get(name)
+    }
+}
+
+/// Reads YAML source files, resolving custom tags:
+/// - `!ref name` a fragment from the anchors library
+/// - `!include path` the contents of another YAML file
+/// - `!file path` the contents of a text file
+/// - `!base64file path` the base64 encoded contents of a file
+/// - `!env NAME` or `!env [NAME, default]` a process environment variable
+///
+/// Paths are relative to the including file and must be within the ev2 root.
+pub struct Loader {
+    ev2_path: Utf8PathBuf,
+    anchors: Anchors,
+}
+
+impl Loader {
+    pub fn new(ev2_path: &Utf8Path, anchors: Anchors) -> Self {
+        Loader {
+            ev2_path: normalize(ev2_path),
+            anchors,
+        }
+    }
+
+    /// Reads a YAML file, resolving custom tags, and converts it to JSON.
+    pub fn read_yml(&self, yml_path: &Utf8Path) -> Result<serde_json::Value> {
+        let value = self.read_yml_value(yml_path, &mut Vec::new())?;
+        to_json(value).with_context(|| format!("converting yml {yml_path}"))
+    }
+
+    fn read_yml_value(&self, yml_path: &Utf8Path, stack: &mut Vec<String>) -> Result<Value> {
+        let mut value: Value = serde_yaml::from_slice(
+            &fs::read(yml_path).with_context(|| format!("reading file {yml_path}"))?,
+        )
+        .with_context(|| format!("reading yml {yml_path}"))?;
+        stack.push(yml_path.to_string());
+        self.resolve_tags(&mut value, yml_path, stack)
+            .with_context(|| format!("resolving tags in {yml_path}"))?;
+        stack.pop();
+        Ok(value)
+    }
+
+    fn resolve_tags(
+        &self,
+        value: &mut Value,
+        yml_path: &Utf8Path,
+        stack: &mut Vec<String>,
+    ) -> Result<()> {
+        match value {
+            Value::Tagged(tagged) => {
+                let tag = tagged.tag.to_string();
+                let arg = &tagged.value;
+                *value = match tag.as_str() {
+                    "!ref" => {
+                        let name = tag_str(&tag, arg)?;
+                        let mut fragment = self
+                            .anchors
+                            .get(name)
+                            .ok_or_else(|| anyhow!("unknown anchor {name}"))?
+                            .clone();
+                        let name = format!("!ref {name}");
+                        if stack.contains(&name) {
+                            bail!("recursive anchor reference {name} via {stack:?}");
+                        }
+                        stack.push(name);
+                        self.resolve_tags(&mut fragment, yml_path, stack)?;
+                        stack.pop();
+                        fragment
+                    }
+                    "!include" => {
+                        let include_path = self.relative_path(yml_path, tag_str(&tag, arg)?)?;
+                        if stack.contains(&include_path.to_string()) {
+                            bail!("recursive include of {include_path} via {stack:?}");
+                        }
+                        self.read_yml_value(&include_path, stack)?
+                    }
+                    "!file" => {
+                        let file_path = self.relative_path(yml_path, tag_str(&tag, arg)?)?;
+                        Value::String(
+                            fs::read_to_string(&file_path)
+                                .with_context(|| format!("reading file {file_path}"))?,
+                        )
+                    }
+                    "!base64file" => {
+                        let file_path = self.relative_path(yml_path, tag_str(&tag, arg)?)?;
+                        Value::String(
+                            STANDARD.encode(
+                                fs::read(&file_path)
+                                    .with_context(|| format!("reading file {file_path}"))?,
+                            ),
+                        )
+                    }
+                    "!env" => env_var(arg)?,
+                    _ => bail!("unsupported tag {tag}"),
+                };
+            }
+            Value::Sequence(seq) => {
+                for item in seq {
+                    self.resolve_tags(item, yml_path, stack)?;
+                }
+            }
+            Value::Mapping(mapping) => {
+                for (_key, item) in mapping.iter_mut() {
+                    self.resolve_tags(item, yml_path, stack)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Resolves a path relative to the directory of yml_path, confined to the ev2 root.
+    fn relative_path(&self, yml_path: &Utf8Path, path: &str) -> Result<Utf8PathBuf> {
+        let dir = yml_path.parent().unwrap_or(Utf8Path::new(""));
+        let resolved = normalize(&dir.join(path));
+        let inside = resolved
+            .strip_prefix(&self.ev2_path)
+            .map(|rest| !rest.components().any(|c| c == Utf8Component::ParentDir))
+            .unwrap_or(false);
+        if !inside {
+            bail!(
+                "{path} referenced from {yml_path} is outside of {}",
+                self.ev2_path
+            );
+        }
+        Ok(resolved)
+    }
+}
+
+fn tag_str<'a>(tag: &str, arg: &'a Value) -> Result<&'a str> {
+    arg.as_str()
+        .ok_or_else(|| anyhow!("{tag} expects a string, found {arg:?}"))
+}
+
+/// `!env NAME` or `!env [NAME, default]`
+fn env_var(arg: &Value) -> Result<Value> {
+    let (name, default) = match arg {
+        Value::Sequence(seq) if seq.len() == 2 => (tag_str("!env", &seq[0])?, Some(&seq[1])),
+        _ => (tag_str("!env", arg)?, None),
+    };
+    match std::env::var(name) {
+        Ok(value) => Ok(Value::String(value)),
+        Err(_) => default
+            .cloned()
+            .ok_or_else(|| anyhow!("environment variable {name} is not set")),
+    }
+}
+
+/// Lexically removes `.` and `..` components.
+fn normalize(path: &Utf8Path) -> Utf8PathBuf {
+    let mut normalized = Utf8PathBuf::new();
+    for component in path.components() {
+        match component {
+            Utf8Component::CurDir => {}
+            Utf8Component::ParentDir => match normalized.components().next_back() {
+                Some(Utf8Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Utf8Component::RootDir) | Some(Utf8Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn scalar_key(key: &Value) -> Result<String> {
+    Ok(match key {
+        Value::String(key) => key.clone(),
+        Value::Number(key) => key.to_string(),
+        Value::Bool(key) => key.to_string(),
+        Value::Null => "null".to_string(),
+        _ => bail!("expected a scalar key, found {key:?}"),
+    })
+}
+
+/// Converts a YAML value without tags to JSON. Scalar keys are converted to strings.
+pub fn to_json(value: Value) -> Result<serde_json::Value> {
+    Ok(match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Bool(b) => serde_json::Value::Bool(b),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                n.into()
+            } else if let Some(n) = n.as_i64() {
+                n.into()
+            } else {
+                let f = n.as_f64().unwrap_or(f64::NAN);
+                serde_json::Number::from_f64(f)
+                    .map(serde_json::Value::Number)
+                    .ok_or_else(|| anyhow!("{n} is not a valid JSON number"))?
+            }
+        }
+        Value::String(s) => serde_json::Value::String(s),
+        Value::Sequence(seq) => seq
+            .into_iter()
+            .map(to_json)
+            .collect::<Result<Vec<_>>>()?
+            .into(),
+        Value::Mapping(mapping) => {
+            let mut map = serde_json::Map::new();
+            for (key, value) in mapping {
+                map.insert(scalar_key(&key)?, to_json(value)?);
+            }
+            serde_json::Value::Object(map)
+        }
+        Value::Tagged(tagged) => bail!("unresolved tag {}", tagged.tag),
+    })
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn anchors(yml: &str) -> Anchors {
+        let mapping: serde_yaml::Mapping = serde_yaml::from_str(yml).unwrap();
+        let fragments = mapping
+            .into_iter()
+            .map(|(k, v)| (k.as_str().unwrap().to_string(), v))
+            .collect();
+        Anchors { fragments }
+    }
+
+    fn resolve(yml: &str, loader: &Loader) -> Result<serde_json::Value> {
+        let mut value: Value = serde_yaml::from_str(yml)?;
+        let yml_path = loader.ev2_path.join("environments/test.yml");
+        loader.resolve_tags(&mut value, &yml_path, &mut Vec::new())?;
+        to_json(value)
+    }
+
+    /// Creates files in a new temporary ev2 directory.
+    pub fn temp_ev2(name: &str, files: &[(&str, &str)]) -> Result<Utf8PathBuf> {
+        let ev2_path = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .map_err(|path| anyhow!("{path:?} is not utf-8"))?
+            .join(format!("configur-{name}-{}", std::process::id()));
+        if ev2_path.exists() {
+            fs::remove_dir_all(&ev2_path)?;
+        }
+        for (path, contents) in files {
+            let path = ev2_path.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, contents)?;
+        }
+        Ok(ev2_path)
+    }
+
+    #[test]
+    fn test_ref() -> Result<()> {
+        let loader = Loader::new(
+            Utf8Path::new("ev2"),
+            anchors(
+                r#"
+cpu/6140:
+  Model: "Intel(R) Xeon(R) Gold 6140 CPU @ 2.30GHz"
+"#,
+            ),
+        );
+        let mut value = resolve(
+            r#"
+Processors:
+  - "6140":
+      <<: !ref cpu/6140
+      CPUCount: 2
+"#,
+            &loader,
+        )?;
+        crate::remove_brackets(&mut value)?;
+        assert_eq!(
+            value,
+            json!({"Processors": [{"6140": {
+                "Model": "Intel(R) Xeon(R) Gold 6140 CPU @ 2.30GHz",
+                "CPUCount": 2
+            }}]})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ref_errors() {
+        let loader = Loader::new(
+            Utf8Path::new("ev2"),
+            anchors("a/x: !ref a/y\na/y: !ref a/x\n"),
+        );
+        let err = resolve("cpu: !ref cpu/1234", &loader).unwrap_err();
+        assert_eq!(err.to_string(), "unknown anchor cpu/1234");
+        let err = resolve("x: !ref a/x", &loader).unwrap_err();
+        assert!(err.to_string().starts_with("recursive anchor reference"));
+    }
+
+    #[test]
+    fn test_file_tags() -> Result<()> {
+        let ev2_path = temp_ev2(
+            "file-tags",
+            &[
+                (
+                    "environments/prod/region.yml",
+                    "network: !include ../../shared/network.yml\ncert: !file cert.pem\nkey: !base64file cert.pem\n",
+                ),
+                ("environments/prod/cert.pem", "CERT"),
+                ("shared/network.yml", "cidr: 10.0.0.0/8\nowner: !file owner.txt\n"),
+                ("shared/owner.txt", "team"),
+                ("environments/escape.yml", "secret: !file ../../secret.txt\n"),
+            ],
+        )?;
+        let loader = Loader::new(&ev2_path, Anchors::default());
+        let value = loader.read_yml(&ev2_path.join("environments/prod/region.yml"))?;
+        assert_eq!(
+            value,
+            json!({
+                "network": {"cidr": "10.0.0.0/8", "owner": "team"},
+                "cert": "CERT",
+                "key": "Q0VSVA=="
+            })
+        );
+        let err = loader
+            .read_yml(&ev2_path.join("environments/escape.yml"))
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("is outside of"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_env() -> Result<()> {
+        let loader = Loader::new(Utf8Path::new("ev2"), Anchors::default());
+        std::env::set_var("CONFIGUR_TEST_ENV", "from env");
+        let value = resolve(
+            "a: !env CONFIGUR_TEST_ENV\nb: !env [CONFIGUR_TEST_UNSET, 3]\n",
+            &loader,
+        )?;
+        assert_eq!(value, json!({"a": "from env", "b": 3}));
+        assert!(resolve("c: !env CONFIGUR_TEST_UNSET", &loader).is_err());
+        Ok(())
+    }
+}